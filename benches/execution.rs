@@ -0,0 +1,94 @@
+//! Compares the execution speed of the vm with and without the decoded instruction cache, and with each execution
+//! engine.
+//!
+//! The games in `example_images` are driven with scripted key presses until they run out of input, and a counting
+//! loop measures raw dispatch speed. Run with `cargo bench`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use lc3_vm::{
+    ExecutionEngine, LC3VirtualMachine, LC3VirtualMachineBuilder, ScriptedConsole, assemble,
+};
+use std::fs;
+use std::io;
+
+/// Runs image until it halts or consumes the whole input, returning the number of instructions executed.
+fn run_workload(builder: LC3VirtualMachineBuilder, image: &[u8], input: &[u8]) -> u64 {
+    let mut vm = builder
+        .console(ScriptedConsole::new(input, io::sink()))
+        .image(image.to_vec())
+        .build()
+        .unwrap();
+    let _ = vm.run();
+    vm.instruction_count
+}
+
+fn workloads() -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+    let counting_loop = assemble(
+        "
+        .ORIG x3000
+        LD R1, COUNT
+LOOP    ADD R2, R2, #1
+        AND R3, R2, #7
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+COUNT   .FILL #20000
+        .END",
+    )
+    .unwrap();
+    vec![
+        ("loop", counting_loop.obj_image(), Vec::new()),
+        (
+            "2048",
+            fs::read("example_images/2048.obj").unwrap(),
+            b"wasd".repeat(25),
+        ),
+        (
+            "rogue",
+            fs::read("example_images/rogue.obj").unwrap(),
+            b"y".iter()
+                .chain(&b"ddddssssaaaawwww".repeat(4))
+                .copied()
+                .collect(),
+        ),
+    ]
+}
+
+fn decode_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_cache");
+    for (name, image, input) in workloads() {
+        let instructions = run_workload(LC3VirtualMachine::builder(), &image, &input);
+        group.throughput(Throughput::Elements(instructions));
+        for enabled in [false, true] {
+            let label = if enabled { "cached" } else { "uncached" };
+            group.bench_with_input(BenchmarkId::new(label, name), &enabled, |b, enabled| {
+                b.iter(|| {
+                    let builder = LC3VirtualMachine::builder().decode_cache(*enabled);
+                    run_workload(builder, &image, &input)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn engines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine");
+    for (name, image, input) in workloads() {
+        let instructions = run_workload(LC3VirtualMachine::builder(), &image, &input);
+        group.throughput(Throughput::Elements(instructions));
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            let label = format!("{:?}", engine);
+            group.bench_with_input(BenchmarkId::new(label, name), &engine, |b, engine| {
+                b.iter(|| {
+                    let builder = LC3VirtualMachine::builder().engine(*engine);
+                    run_workload(builder, &image, &input)
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode_cache, engines);
+criterion_main!(benches);
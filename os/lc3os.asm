@@ -0,0 +1,188 @@
+; Operating system loaded by default into the LC3 virtual machine.
+;
+; It fills the trap vector table with service routines written in LC3 code over the device registers, so the TRAP
+; instruction jumps to memory resident code instead of running the routine natively in the vm. TRAP leaves the
+; return address in R7 and pushes the PSR and the PC onto the supervisor stack, switching to it when the program runs
+; in user mode, so the routines run in supervisor mode and return with RTI. They preserve every register except R0
+; when it holds a result.
+; No exception or interrupt handlers are installed, so exceptions stop the vm with an error.
+
+        .ORIG x0000
+
+; Trap vector table (x0000 - x00FF)
+        .BLKW x20
+        .FILL TRAP_GETC         ; x20
+        .FILL TRAP_OUT          ; x21
+        .FILL TRAP_PUTS         ; x22
+        .FILL TRAP_IN           ; x23
+        .FILL TRAP_PUTSP        ; x24
+        .FILL TRAP_HALT         ; x25
+        .BLKW xDA
+
+; Interrupt vector table (x0100 - x01FF)
+        .BLKW x100
+
+; GETC: waits for a key and stores its character in R0 without echoing it.
+TRAP_GETC
+        ST R7, GETC_SAVE_R7
+        JSR READ_CHAR
+        LD R7, GETC_SAVE_R7
+        RTI
+
+; OUT: writes the character in R0 to the display.
+TRAP_OUT
+        ST R7, OUT_SAVE_R7
+        JSR WRITE_CHAR
+        LD R7, OUT_SAVE_R7
+        RTI
+
+; PUTS: writes the string starting at the address in R0, one character per word, until a zero word.
+TRAP_PUTS
+        ST R7, PUTS_SAVE_R7
+        JSR WRITE_STRING
+        LD R7, PUTS_SAVE_R7
+        RTI
+
+; IN: prompts for a character, echoes it and stores it in R0.
+TRAP_IN
+        ST R1, IN_SAVE_R1
+        ST R7, IN_SAVE_R7
+        LEA R0, IN_PROMPT
+        JSR WRITE_STRING
+        JSR READ_CHAR
+        JSR WRITE_CHAR
+        LDR R1, R6, #1          ; RTI restores the condition codes from the saved PSR, so they are set there
+        AND R1, R1, #-8
+        ADD R0, R0, #0
+        BRz IN_ZERO
+        ADD R1, R1, #1          ; Pos, a character is never negative
+        BRnzp IN_DONE
+IN_ZERO
+        ADD R1, R1, #2          ; Zro
+IN_DONE
+        STR R1, R6, #1
+        LD R1, IN_SAVE_R1
+        LD R7, IN_SAVE_R7
+        RTI
+
+; PUTSP: writes the string starting at the address in R0, two characters per word with the first one in the low
+; byte, until a zero byte.
+TRAP_PUTSP
+        ST R0, PUTSP_SAVE_R0
+        ST R1, PUTSP_SAVE_R1
+        ST R2, PUTSP_SAVE_R2
+        ST R3, PUTSP_SAVE_R3
+        ST R4, PUTSP_SAVE_R4
+        ST R7, PUTSP_SAVE_R7
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R3, R1, #0
+        LD R0, LOW_BYTE
+        AND R0, R3, R0
+        BRz PUTSP_DONE
+        JSR WRITE_CHAR
+        AND R2, R2, #0          ; The high byte is shifted into R2 one bit at a time
+        AND R4, R4, #0
+        ADD R4, R4, #8
+PUTSP_SHIFT
+        ADD R2, R2, R2
+        ADD R3, R3, #0
+        BRzp PUTSP_NEXT_BIT
+        ADD R2, R2, #1
+PUTSP_NEXT_BIT
+        ADD R3, R3, R3
+        ADD R4, R4, #-1
+        BRp PUTSP_SHIFT
+        ADD R0, R2, #0
+        BRz PUTSP_DONE
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BRnzp PUTSP_LOOP
+PUTSP_DONE
+        LD R0, PUTSP_SAVE_R0
+        LD R1, PUTSP_SAVE_R1
+        LD R2, PUTSP_SAVE_R2
+        LD R3, PUTSP_SAVE_R3
+        LD R4, PUTSP_SAVE_R4
+        LD R7, PUTSP_SAVE_R7
+        RTI
+
+; HALT: stops the clock clearing bit 15 of the machine control register. The vm stops with every register of the
+; program but R7 and the condition codes, which are restored before returning, so they can be checked once it halted.
+; If the clock is started again the program continues after the TRAP instruction.
+TRAP_HALT
+        ST R0, HALT_SAVE_R0
+        LDI R0, MCR
+        LD R7, CLOCK_OFF
+        AND R7, R0, R7
+        LD R0, HALT_SAVE_R0
+        STI R7, MCR
+        LDR R7, R6, #0          ; Return address saved by TRAP
+        RTI
+
+; Subroutines shared by the trap routines, called with JSR and returning with RET.
+
+; READ_CHAR: waits for a key and stores its character in R0.
+READ_CHAR
+        LDI R0, KBSR
+        BRzp READ_CHAR
+        LDI R0, KBDR
+        RET
+
+; WRITE_CHAR: writes the character in R0 to the display.
+WRITE_CHAR
+        ST R1, WRITE_SAVE_R1
+WRITE_WAIT
+        LDI R1, DSR
+        BRzp WRITE_WAIT
+        STI R0, DDR
+        LD R1, WRITE_SAVE_R1
+        RET
+
+; WRITE_STRING: writes the string starting at the address in R0, one character per word, until a zero word.
+WRITE_STRING
+        ST R0, STRING_SAVE_R0
+        ST R1, STRING_SAVE_R1
+        ST R7, STRING_SAVE_R7
+        ADD R1, R0, #0
+STRING_LOOP
+        LDR R0, R1, #0
+        BRz STRING_DONE
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BRnzp STRING_LOOP
+STRING_DONE
+        LD R0, STRING_SAVE_R0
+        LD R1, STRING_SAVE_R1
+        LD R7, STRING_SAVE_R7
+        RET
+
+; Device registers
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+DSR     .FILL xFE04
+DDR     .FILL xFE06
+MCR     .FILL xFFFE
+
+LOW_BYTE    .FILL x00FF
+CLOCK_OFF   .FILL x7FFF
+IN_PROMPT   .STRINGZ "Enter a character: \n"
+
+GETC_SAVE_R7    .BLKW 1
+OUT_SAVE_R7     .BLKW 1
+PUTS_SAVE_R7    .BLKW 1
+IN_SAVE_R1      .BLKW 1
+IN_SAVE_R7      .BLKW 1
+PUTSP_SAVE_R0   .BLKW 1
+PUTSP_SAVE_R1   .BLKW 1
+PUTSP_SAVE_R2   .BLKW 1
+PUTSP_SAVE_R3   .BLKW 1
+PUTSP_SAVE_R4   .BLKW 1
+PUTSP_SAVE_R7   .BLKW 1
+HALT_SAVE_R0    .BLKW 1
+WRITE_SAVE_R1   .BLKW 1
+STRING_SAVE_R0  .BLKW 1
+STRING_SAVE_R1  .BLKW 1
+STRING_SAVE_R7  .BLKW 1
+
+        .END
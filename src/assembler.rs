@@ -0,0 +1,759 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::hardware::TrapCode;
+
+#[derive(PartialEq, Debug)]
+pub enum AssemblerError {
+    MissingOrig(usize),
+    MissingEnd(usize),
+    UnknownOpcode(usize, String),
+    InvalidOperand(usize, String),
+    WrongNumberOfOperands(usize, String),
+    UndefinedLabel(usize, String),
+    DuplicateLabel(usize, String),
+    InvalidLabel(usize, String),
+    ValueOutOfRange(usize, String),
+    InvalidString(usize),
+    AddressOutOfMemory(usize),
+}
+
+impl AssemblerError {
+    /// Returns the line of the source file where the error was found.
+    pub fn line(&self) -> usize {
+        match *self {
+            AssemblerError::MissingOrig(line)
+            | AssemblerError::MissingEnd(line)
+            | AssemblerError::UnknownOpcode(line, _)
+            | AssemblerError::InvalidOperand(line, _)
+            | AssemblerError::WrongNumberOfOperands(line, _)
+            | AssemblerError::UndefinedLabel(line, _)
+            | AssemblerError::DuplicateLabel(line, _)
+            | AssemblerError::InvalidLabel(line, _)
+            | AssemblerError::ValueOutOfRange(line, _)
+            | AssemblerError::InvalidString(line)
+            | AssemblerError::AddressOutOfMemory(line) => line,
+        }
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            AssemblerError::MissingOrig(_) => String::from("Expected .ORIG directive"),
+            AssemblerError::MissingEnd(_) => String::from("Missing .END directive"),
+            AssemblerError::UnknownOpcode(_, value) => format!("Unknown opcode: {}", value),
+            AssemblerError::InvalidOperand(_, value) => format!("Invalid operand: {}", value),
+            AssemblerError::WrongNumberOfOperands(_, value) => {
+                format!("Wrong number of operands for {}", value)
+            }
+            AssemblerError::UndefinedLabel(_, value) => format!("Undefined label: {}", value),
+            AssemblerError::DuplicateLabel(_, value) => format!("Duplicate label: {}", value),
+            AssemblerError::InvalidLabel(_, value) => {
+                format!("{} is a reserved word and can't be a label", value)
+            }
+            AssemblerError::ValueOutOfRange(_, value) => format!("Value out of range: {}", value),
+            AssemblerError::InvalidString(_) => String::from("Invalid string literal"),
+            AssemblerError::AddressOutOfMemory(_) => String::from("Program does not fit in memory"),
+        };
+        write!(f, "Line {}: {}", self.line(), description)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// Result of assembling a source file.
+#[derive(PartialEq, Debug)]
+pub struct AssembledProgram {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Labels with their addresses, in the order they were defined.
+    pub symbols: Vec<(String, u16)>,
+    /// Address of every instruction with the source line it was assembled from.
+    pub lines: Vec<(u16, usize)>,
+}
+
+impl AssembledProgram {
+    /// Returns the object image the vm loads: the origin followed by every word, all in big endian format.
+    pub fn obj_image(&self) -> Vec<u8> {
+        let mut image = Vec::with_capacity(self.words.len() * 2 + 2);
+        image.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            image.extend_from_slice(&word.to_be_bytes());
+        }
+        image
+    }
+
+    /// Returns the symbol table in the same format as the .sym files of the LC3 tools.
+    pub fn sym_file(&self) -> String {
+        let mut sym_file = String::from(
+            "// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n",
+        );
+        for (label, address) in &self.symbols {
+            sym_file.push_str(&format!("//\t{:<16}  {:04X}\n", label, address));
+        }
+        sym_file
+    }
+
+    /// Returns the line table of the program, which maps every instruction to its line in the source file at
+    /// source_path. The format is described in [`crate::symbols::DebugInfo`].
+    pub fn debug_file(&self, source_path: &str) -> String {
+        let mut debug_file = format!("source {}\n", source_path);
+        for (address, line) in &self.lines {
+            debug_file.push_str(&format!("{:04X} {}\n", address, line));
+        }
+        debug_file
+    }
+}
+
+/// Source line split into its parts. Operands keep the text they had in the source.
+struct Statement {
+    line: usize,
+    address: u16,
+    operation: String,
+    operands: Vec<String>,
+}
+
+const DIRECTIVES: [&str; 5] = [".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END"];
+
+const OPCODES: [&str; 22] = [
+    "ADD", "AND", "NOT", "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI",
+    "STR", "RTI", "TRAP", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT",
+];
+
+/// Checks if the word is a branch mnemonic: BR followed by an optional combination of n, z and p in that order.
+fn is_branch(word: &str) -> bool {
+    let upper = word.to_uppercase();
+    match upper.strip_prefix("BR") {
+        Some(flags) => ["", "N", "Z", "P", "NZ", "NP", "ZP", "NZP"].contains(&flags),
+        None => false,
+    }
+}
+
+fn is_operation(word: &str) -> bool {
+    let upper = word.to_uppercase();
+    OPCODES.contains(&upper.as_str()) || DIRECTIVES.contains(&upper.as_str()) || is_branch(word)
+}
+
+/// Splits a source line into tokens. Commas and whitespaces separate tokens, comments start with ';' and strings
+/// are kept as a single token including their quotes.
+fn tokenize(source_line: &str, line: usize) -> Result<Vec<String>, AssemblerError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = source_line.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            ';' => break,
+            '"' => {
+                if !current.is_empty() {
+                    return Err(AssemblerError::InvalidString(line));
+                }
+                current.push(char);
+                let mut closed = false;
+                while let Some(char) = chars.next() {
+                    current.push(char);
+                    if char == '\\' {
+                        current.push(chars.next().ok_or(AssemblerError::InvalidString(line))?);
+                    } else if char == '"' {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(AssemblerError::InvalidString(line));
+                }
+                tokens.push(std::mem::take(&mut current));
+            }
+            ',' | ' ' | '\t' | '\r' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(char),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+/// Parses a numeric literal. Decimal numbers can be written as #10 or 10 and hexadecimal numbers as x1F or 0x1F.
+pub(crate) fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(decimal) = digits.strip_prefix('#') {
+        decimal.parse::<i32>().ok()?
+    } else if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .or_else(|| digits.strip_prefix('x'))
+        .or_else(|| digits.strip_prefix('X'))
+    {
+        if hex.is_empty() || !hex.chars().all(|char| char.is_ascii_hexdigit()) {
+            return None;
+        }
+        i32::from_str_radix(hex, 16).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|char| char.is_ascii_digit()) {
+        digits.parse::<i32>().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parses the content of a string literal, resolving escape sequences.
+fn parse_string(token: &str, line: usize) -> Result<Vec<u16>, AssemblerError> {
+    let content = token
+        .strip_prefix('"')
+        .and_then(|token| token.strip_suffix('"'))
+        .ok_or(AssemblerError::InvalidString(line))?;
+    let mut words = Vec::new();
+    let mut chars = content.chars();
+    while let Some(char) = chars.next() {
+        let char = if char == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('e') => '\x1B',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                _ => return Err(AssemblerError::InvalidString(line)),
+            }
+        } else {
+            char
+        };
+        if !char.is_ascii() {
+            return Err(AssemblerError::InvalidString(line));
+        }
+        words.push(char as u16);
+    }
+    words.push(0);
+    Ok(words)
+}
+
+fn is_valid_label(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+                && parse_number(token).is_none()
+                && !is_operation(token)
+                && !is_register(token)
+        }
+        _ => false,
+    }
+}
+
+/// Checks if the word is a register name, R0 to R7 in any case.
+fn is_register(word: &str) -> bool {
+    parse_register(word, 0).is_ok()
+}
+
+fn parse_register(token: &str, line: usize) -> Result<u16, AssemblerError> {
+    let upper = token.to_uppercase();
+    match upper
+        .strip_prefix('R')
+        .and_then(|number| number.parse::<u16>().ok())
+    {
+        Some(register) if register < 8 && upper.len() == 2 => Ok(register),
+        _ => Err(AssemblerError::InvalidOperand(line, String::from(token))),
+    }
+}
+
+/// Checks that a value fits in a two's complement immediate of the given number of bits and returns it masked.
+fn signed_immediate(
+    value: i32,
+    bits: u32,
+    token: &str,
+    line: usize,
+) -> Result<u16, AssemblerError> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        return Err(AssemblerError::ValueOutOfRange(line, String::from(token)));
+    }
+    Ok((value as u16) & ((1 << bits) - 1))
+}
+
+/// Computes the number of words an statement takes in memory.
+fn statement_size(
+    operation: &str,
+    operands: &[String],
+    line: usize,
+) -> Result<u16, AssemblerError> {
+    match operation {
+        ".BLKW" => {
+            let [count] = operands else {
+                return Err(AssemblerError::WrongNumberOfOperands(
+                    line,
+                    String::from(operation),
+                ));
+            };
+            match parse_number(count) {
+                Some(value) if (1..=0xFFFF).contains(&value) => Ok(value as u16),
+                Some(_) => Err(AssemblerError::ValueOutOfRange(line, count.clone())),
+                None => Err(AssemblerError::InvalidOperand(line, count.clone())),
+            }
+        }
+        ".STRINGZ" => {
+            let [string] = operands else {
+                return Err(AssemblerError::WrongNumberOfOperands(
+                    line,
+                    String::from(operation),
+                ));
+            };
+            Ok(parse_string(string, line)?.len() as u16)
+        }
+        _ => Ok(1),
+    }
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: Vec<(String, u16)>,
+    addresses: HashMap<String, u16>,
+    origin: u16,
+}
+
+impl Assembler {
+    /// First pass: splits every line into statements, assigns addresses to them and builds the symbol table.
+    fn first_pass(source: &str) -> Result<Self, AssemblerError> {
+        let mut assembler = Self {
+            statements: Vec::new(),
+            symbols: Vec::new(),
+            addresses: HashMap::new(),
+            origin: 0,
+        };
+        let mut current_address: Option<u32> = None;
+        let mut last_line = 0;
+        for (index, source_line) in source.lines().enumerate() {
+            let line = index + 1;
+            last_line = line;
+            let mut tokens = tokenize(source_line, line)?;
+            if tokens.is_empty() {
+                continue;
+            }
+            let mut label = None;
+            if !is_operation(&tokens[0]) {
+                let name = tokens.remove(0);
+                let name = name.strip_suffix(':').unwrap_or(&name).to_string();
+                if is_register(&name) {
+                    return Err(AssemblerError::InvalidLabel(line, name));
+                }
+                if !is_valid_label(&name) {
+                    return Err(AssemblerError::UnknownOpcode(line, name));
+                }
+                label = Some(name);
+            }
+            let Some(address) = current_address else {
+                // The only statement allowed before .ORIG is .ORIG itself.
+                match tokens.first() {
+                    Some(operation) if operation.to_uppercase() == ".ORIG" && label.is_none() => {
+                        let [origin] = &tokens[1..] else {
+                            return Err(AssemblerError::WrongNumberOfOperands(
+                                line,
+                                String::from(".ORIG"),
+                            ));
+                        };
+                        assembler.origin = match parse_number(origin) {
+                            Some(value) if (0..=0xFFFF).contains(&value) => value as u16,
+                            Some(_) => {
+                                return Err(AssemblerError::ValueOutOfRange(line, origin.clone()));
+                            }
+                            None => {
+                                return Err(AssemblerError::InvalidOperand(line, origin.clone()));
+                            }
+                        };
+                        current_address = Some(assembler.origin as u32);
+                        continue;
+                    }
+                    _ => return Err(AssemblerError::MissingOrig(line)),
+                }
+            };
+            if let Some(label) = label {
+                if assembler.addresses.contains_key(&label) {
+                    return Err(AssemblerError::DuplicateLabel(line, label));
+                }
+                assembler.addresses.insert(label.clone(), address as u16);
+                assembler.symbols.push((label, address as u16));
+            }
+            if tokens.is_empty() {
+                continue; // Line with only a label
+            }
+            let operation = tokens.remove(0).to_uppercase();
+            match operation.as_str() {
+                ".END" => return Ok(assembler),
+                ".ORIG" => {
+                    return Err(AssemblerError::InvalidOperand(line, operation));
+                }
+                _ => {}
+            }
+            let size = statement_size(&operation, &tokens, line)? as u32;
+            if address + size > 0x10000 {
+                return Err(AssemblerError::AddressOutOfMemory(line));
+            }
+            assembler.statements.push(Statement {
+                line,
+                address: address as u16,
+                operation,
+                operands: tokens,
+            });
+            current_address = Some(address + size);
+        }
+        match current_address {
+            Some(_) => Err(AssemblerError::MissingEnd(last_line)),
+            None => Err(AssemblerError::MissingOrig(last_line)),
+        }
+    }
+
+    /// Resolves an operand that can be either a label or a number to its value.
+    fn value(&self, token: &str, line: usize) -> Result<i32, AssemblerError> {
+        if let Some(value) = parse_number(token) {
+            return Ok(value);
+        }
+        match self.addresses.get(token) {
+            Some(address) => Ok(*address as i32),
+            None if is_valid_label(token) => {
+                Err(AssemblerError::UndefinedLabel(line, String::from(token)))
+            }
+            None => Err(AssemblerError::InvalidOperand(line, String::from(token))),
+        }
+    }
+
+    /// Resolves a PC relative operand. Labels are turned into an offset from the incremented PC, numbers are used as
+    /// the offset itself.
+    fn pc_offset(
+        &self,
+        token: &str,
+        statement: &Statement,
+        bits: u32,
+    ) -> Result<u16, AssemblerError> {
+        let offset = match parse_number(token) {
+            Some(value) => value,
+            None => self.value(token, statement.line)? - (statement.address as i32 + 1),
+        };
+        signed_immediate(offset, bits, token, statement.line)
+    }
+
+    /// Second pass: encodes every statement into its words.
+    fn second_pass(&self) -> Result<Vec<u16>, AssemblerError> {
+        let mut words = Vec::new();
+        for statement in &self.statements {
+            words.extend(self.encode(statement)?);
+        }
+        Ok(words)
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u16>, AssemblerError> {
+        let line = statement.line;
+        let operation = statement.operation.as_str();
+        let operands: Vec<&str> = statement.operands.iter().map(String::as_str).collect();
+        let wrong_operands =
+            || AssemblerError::WrongNumberOfOperands(line, String::from(operation));
+        let word = match (operation, operands.as_slice()) {
+            (".FILL", [value]) => {
+                let number = self.value(value, line)?;
+                if !(-0x8000..=0xFFFF).contains(&number) {
+                    return Err(AssemblerError::ValueOutOfRange(line, String::from(*value)));
+                }
+                number as u16
+            }
+            (".BLKW", _) => {
+                return Ok(vec![
+                    0;
+                    statement_size(operation, &statement.operands, line)?
+                        as usize
+                ]);
+            }
+            (".STRINGZ", [string]) => return parse_string(string, line),
+            ("ADD", [dst, src, operand]) | ("AND", [dst, src, operand]) => {
+                let op_code: u16 = if operation == "ADD" { 0b0001 } else { 0b0101 };
+                let base = op_code << 12
+                    | parse_register(dst, line)? << 9
+                    | parse_register(src, line)? << 6;
+                match parse_register(operand, line) {
+                    Ok(register) => base | register,
+                    Err(_) => {
+                        let immediate = self.value(operand, line)?;
+                        base | 1 << 5 | signed_immediate(immediate, 5, operand, line)?
+                    }
+                }
+            }
+            ("NOT", [dst, src]) => {
+                0b1001 << 12
+                    | parse_register(dst, line)? << 9
+                    | parse_register(src, line)? << 6
+                    | 0x3F
+            }
+            (_, [target]) if is_branch(operation) => {
+                let mut flags = 0;
+                let conditions = &operation[2..];
+                if conditions.is_empty() {
+                    flags = 0b111;
+                }
+                if conditions.contains('N') {
+                    flags |= 0b100;
+                }
+                if conditions.contains('Z') {
+                    flags |= 0b010;
+                }
+                if conditions.contains('P') {
+                    flags |= 0b001;
+                }
+                flags << 9 | self.pc_offset(target, statement, 9)?
+            }
+            ("JMP", [base]) => 0b1100 << 12 | parse_register(base, line)? << 6,
+            ("RET", []) => 0b1100 << 12 | 7 << 6,
+            ("RTI", []) => 0b1000 << 12,
+            ("JSR", [target]) => 0b0100 << 12 | 1 << 11 | self.pc_offset(target, statement, 11)?,
+            ("JSRR", [base]) => 0b0100 << 12 | parse_register(base, line)? << 6,
+            ("LD", [dst, target])
+            | ("LDI", [dst, target])
+            | ("LEA", [dst, target])
+            | ("ST", [dst, target])
+            | ("STI", [dst, target]) => {
+                let op_code: u16 = match operation {
+                    "LD" => 0b0010,
+                    "LDI" => 0b1010,
+                    "LEA" => 0b1110,
+                    "ST" => 0b0011,
+                    _ => 0b1011,
+                };
+                op_code << 12
+                    | parse_register(dst, line)? << 9
+                    | self.pc_offset(target, statement, 9)?
+            }
+            ("LDR", [dst, base, offset]) | ("STR", [dst, base, offset]) => {
+                let op_code: u16 = if operation == "LDR" { 0b0110 } else { 0b0111 };
+                let offset_value = self.value(offset, line)?;
+                op_code << 12
+                    | parse_register(dst, line)? << 9
+                    | parse_register(base, line)? << 6
+                    | signed_immediate(offset_value, 6, offset, line)?
+            }
+            ("TRAP", [vector]) => {
+                let value = self.value(vector, line)?;
+                if !(0..=0xFF).contains(&value) {
+                    return Err(AssemblerError::ValueOutOfRange(line, String::from(*vector)));
+                }
+                0b1111 << 12 | value as u16
+            }
+            ("GETC", []) => 0xF000 | TrapCode::Getc as u16,
+            ("OUT", []) => 0xF000 | TrapCode::Out as u16,
+            ("PUTS", []) => 0xF000 | TrapCode::Puts as u16,
+            ("IN", []) => 0xF000 | TrapCode::In as u16,
+            ("PUTSP", []) => 0xF000 | TrapCode::Putsp as u16,
+            ("HALT", []) => 0xF000 | TrapCode::Halt as u16,
+            _ if is_operation(operation) => return Err(wrong_operands()),
+            _ => return Err(AssemblerError::UnknownOpcode(line, String::from(operation))),
+        };
+        Ok(vec![word])
+    }
+}
+
+/// Assembles LC3 assembly source code into a program the vm can load.
+pub fn assemble(source: &str) -> Result<AssembledProgram, AssemblerError> {
+    let assembler = Assembler::first_pass(source)?;
+    let words = assembler.second_pass()?;
+    let lines = assembler
+        .statements
+        .iter()
+        .filter(|statement| !DIRECTIVES.contains(&statement.operation.as_str()))
+        .map(|statement| (statement.address, statement.line))
+        .collect();
+    Ok(AssembledProgram {
+        origin: assembler.origin,
+        words,
+        symbols: assembler.symbols,
+        lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::lc3_vm::{LC3VirtualMachine, read_image_file};
+
+    #[test]
+    fn assembling_every_instruction() {
+        let source = "
+            .ORIG x3000
+    START   ADD R0, R1, R2
+            ADD R0, R1, #-1
+            AND R3, R4, x5
+            NOT R5, R6
+            BRnzp START
+            BR START
+            BRz NEXT
+    NEXT    JMP R2
+            RET
+            JSR START
+            JSRR R3
+            LD R1, DATA
+            LDI R1, DATA
+            LDR R2, R3, #-2
+            LEA R4, START
+            ST R1, DATA
+            STI R1, DATA
+            STR R2, R3, #5
+            RTI
+            TRAP x25
+            GETC
+            OUT
+            PUTS
+            IN
+            PUTSP
+            HALT
+    DATA    .FILL xFFFF
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(
+            program.words,
+            vec![
+                0x1042, 0x107F, 0x5725, 0x9BBF, 0x0FFB, 0x0FFA, 0x0400, 0xC080, 0xC1C0, 0x4FF6,
+                0x40C0, 0x220E, 0xA20D, 0x64FE, 0xE9F1, 0x320A, 0xB209, 0x74C5, 0x8000, 0xF025,
+                0xF020, 0xF021, 0xF022, 0xF023, 0xF024, 0xF025, 0xFFFF
+            ]
+        );
+        assert_eq!(
+            program.symbols,
+            vec![
+                (String::from("START"), 0x3000),
+                (String::from("NEXT"), 0x3007),
+                (String::from("DATA"), 0x301A)
+            ]
+        );
+    }
+
+    #[test]
+    fn assembling_directives() {
+        let source = "
+            .orig x4000
+            .FILL LABEL
+            .BLKW 2
+    LABEL   .STRINGZ \"a;\\n\"
+            .FILL #-2
+            .end
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.origin, 0x4000);
+        assert_eq!(
+            program.words,
+            vec![0x4003, 0, 0, 'a' as u16, ';' as u16, '\n' as u16, 0, 0xFFFE]
+        );
+        assert_eq!(program.obj_image()[0..4], [0x40, 0x00, 0x40, 0x03]);
+    }
+
+    #[test]
+    fn symbol_table_file() {
+        let program = assemble(".ORIG x3000\nLOOP BR LOOP\n.END").unwrap();
+        assert!(program.sym_file().ends_with("//\tLOOP              3000\n"));
+    }
+
+    #[test]
+    fn debug_file_maps_instructions_to_lines() {
+        let program = assemble(".ORIG x3000\nLOOP BR LOOP\n\nDATA .FILL #1\nHALT\n.END").unwrap();
+        assert_eq!(program.lines, vec![(0x3000, 2), (0x3002, 5)]);
+        assert_eq!(
+            program.debug_file("loop.asm"),
+            "source loop.asm\n3000 2\n3002 5\n"
+        );
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        assert_eq!(
+            assemble("ADD R0, R0, R0\n.END"),
+            Err(AssemblerError::MissingOrig(1))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\n\nBR NOWHERE\n.END"),
+            Err(AssemblerError::UndefinedLabel(3, String::from("NOWHERE")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nA ADD R0, R0, #1\nA HALT\n.END"),
+            Err(AssemblerError::DuplicateLabel(3, String::from("A")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nADD R0, R0, #16\n.END"),
+            Err(AssemblerError::ValueOutOfRange(2, String::from("#16")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nADD R0, R8, R1\n.END"),
+            Err(AssemblerError::InvalidOperand(2, String::from("R8")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nNOT R0\n.END"),
+            Err(AssemblerError::WrongNumberOfOperands(
+                2,
+                String::from("NOT")
+            ))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nHALT"),
+            Err(AssemblerError::MissingEnd(2))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nLABEL MOV R0, R1\n.END")
+                .unwrap_err()
+                .to_string(),
+            "Line 2: Unknown opcode: MOV"
+        );
+    }
+
+    #[test]
+    fn labels_cant_be_reserved_words() {
+        assert_eq!(
+            assemble(".ORIG x3000\nR1 ADD R0, R0, #1\n.END"),
+            Err(AssemblerError::InvalidLabel(2, String::from("R1")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nr7: HALT\n.END"),
+            Err(AssemblerError::InvalidLabel(2, String::from("r7")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nLD R0, R1\n.END"),
+            Err(AssemblerError::InvalidOperand(2, String::from("R1")))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nPUTS HALT\n.END"),
+            Err(AssemblerError::WrongNumberOfOperands(
+                2,
+                String::from("PUTS")
+            ))
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nLD R0, getc\n.END"),
+            Err(AssemblerError::InvalidOperand(2, String::from("getc")))
+        );
+    }
+
+    #[test]
+    fn assembled_program_runs_on_the_vm() {
+        let source = "
+            .ORIG x3000
+            LEA R0, HELLO
+            PUTS
+            HALT
+    HELLO   .STRINGZ \"Hello\"
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        let mut vm = LC3VirtualMachine::new();
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        vm.set_console(Box::new(console));
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        vm.set_pc_with_origin();
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(*output.lock().unwrap(), b"Hello");
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::decode_cache::CachedInstruction;
+use crate::hardware::{Instruction, is_privileged_address};
+use crate::lc3_vm::{LC3VirtualMachine, VMError};
+
+/// Longest block compiled, so a long run of straight-line code doesn't delay checking the limits and the interrupts.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// Compiled instruction. It's called with the PC already pointing to the next instruction, like the interpreter does.
+pub type CompiledInstruction =
+    Box<dyn Fn(&mut LC3VirtualMachine) -> Result<(), VMError> + Send + Sync>;
+
+/// Straight-line code compiled into closures, one per instruction. Only the last instruction of a block can change
+/// the flow of the program, and it runs in the interpreter.
+pub struct Block {
+    pub start: u16,
+    /// Words the block was compiled from, to detect code changed without going through the vm.
+    pub words: Vec<u16>,
+    pub instructions: Vec<CompiledInstruction>,
+}
+
+fn extend_sign(value: u16, bits: u32) -> u16 {
+    (((value << (16 - bits)) as i16) >> (16 - bits)) as u16
+}
+
+fn set_flags(vm: &mut LC3VirtualMachine, value: u16) {
+    vm.update_flags(value);
+}
+
+/// Compiles an instruction that doesn't change the flow of the program, or returns None if it does.
+/// The PC relative addresses are resolved at compile time, since the instruction is always at address.
+fn compile_instruction(address: u16, word: u16) -> Option<CompiledInstruction> {
+    let instruction = CachedInstruction::decode(word).ok()?;
+    let decoded = instruction.decoded;
+    let dst = decoded.dst as usize;
+    let src = decoded.src as usize;
+    let next = address.wrapping_add(1);
+    let pc_relative = next.wrapping_add(extend_sign(decoded.imm9, 9));
+    let offset6 = extend_sign(decoded.imm6, 6);
+    let compiled: CompiledInstruction = match instruction.instruction {
+        Instruction::OpADD if decoded.mode_alu == 1 => {
+            let immediate = extend_sign(decoded.alu_operand2, 5);
+            Box::new(move |vm| {
+                let result = vm.registers[src].wrapping_add(immediate);
+                vm.registers[dst] = result;
+                set_flags(vm, result);
+                Ok(())
+            })
+        }
+        Instruction::OpADD => {
+            let src2 = (decoded.alu_operand2 & 0x7) as usize;
+            Box::new(move |vm| {
+                let result = vm.registers[src].wrapping_add(vm.registers[src2]);
+                vm.registers[dst] = result;
+                set_flags(vm, result);
+                Ok(())
+            })
+        }
+        Instruction::OpAND if decoded.mode_alu == 1 => {
+            let immediate = extend_sign(decoded.alu_operand2, 5);
+            Box::new(move |vm| {
+                let result = vm.registers[src] & immediate;
+                vm.registers[dst] = result;
+                set_flags(vm, result);
+                Ok(())
+            })
+        }
+        Instruction::OpAND => {
+            let src2 = (decoded.alu_operand2 & 0x7) as usize;
+            Box::new(move |vm| {
+                let result = vm.registers[src] & vm.registers[src2];
+                vm.registers[dst] = result;
+                set_flags(vm, result);
+                Ok(())
+            })
+        }
+        Instruction::OpNOT => Box::new(move |vm| {
+            let result = !vm.registers[src];
+            vm.registers[dst] = result;
+            set_flags(vm, result);
+            Ok(())
+        }),
+        Instruction::OpLEA => Box::new(move |vm| {
+            vm.registers[dst] = pc_relative;
+            set_flags(vm, pc_relative);
+            Ok(())
+        }),
+        Instruction::OpLD => Box::new(move |vm| {
+            let value = vm.mem_read(pc_relative)?;
+            vm.registers[dst] = value;
+            set_flags(vm, value);
+            Ok(())
+        }),
+        Instruction::OpLDI => Box::new(move |vm| {
+            let pointer = vm.mem_read(pc_relative)?;
+            let value = vm.mem_read(pointer)?;
+            vm.registers[dst] = value;
+            set_flags(vm, value);
+            Ok(())
+        }),
+        Instruction::OpLDR => Box::new(move |vm| {
+            let value = vm.mem_read(vm.registers[src].wrapping_add(offset6))?;
+            vm.registers[dst] = value;
+            set_flags(vm, value);
+            Ok(())
+        }),
+        Instruction::OpST => Box::new(move |vm| vm.mem_write(pc_relative, vm.registers[dst])),
+        Instruction::OpSTI => Box::new(move |vm| {
+            let pointer = vm.mem_read(pc_relative)?;
+            vm.mem_write(pointer, vm.registers[dst])
+        }),
+        Instruction::OpSTR => Box::new(move |vm| {
+            vm.mem_write(vm.registers[src].wrapping_add(offset6), vm.registers[dst])
+        }),
+        Instruction::OpBR
+        | Instruction::OpJSR
+        | Instruction::OpJMP
+        | Instruction::OpTRAP
+        | Instruction::OpRTI
+        | Instruction::OpRES => return None,
+    };
+    Some(compiled)
+}
+
+/// Compiles the block starting at start. The block ends with the first instruction that changes the flow of the
+/// program, which runs in the interpreter, or before leaving the privilege region of start.
+pub fn compile_block(memory: &[u16], start: u16) -> Block {
+    let privileged = is_privileged_address(start);
+    let mut block = Block {
+        start,
+        words: Vec::new(),
+        instructions: Vec::new(),
+    };
+    let mut address = start;
+    while block.instructions.len() < MAX_BLOCK_LENGTH
+        && (address == start || is_privileged_address(address) == privileged)
+        && (address as usize) < memory.len()
+    {
+        let word = memory[address as usize];
+        block.words.push(word);
+        match compile_instruction(address, word) {
+            Some(compiled) => block.instructions.push(compiled),
+            None => {
+                block.instructions.push(Box::new(move |vm| {
+                    vm.execute_instruction(CachedInstruction::decode(word)?)
+                }));
+                break;
+            }
+        }
+        if address == u16::MAX {
+            break;
+        }
+        address += 1;
+    }
+    block
+}
+
+/// Compiled blocks by start address.
+///
+/// When the vm writes to an address that belongs to any block every block is dropped, since self-modifying code is
+/// rare enough to not deserve tracking which blocks overlap.
+pub struct BlockCache {
+    blocks: HashMap<u16, Arc<Block>>,
+    /// Addresses that belong to a compiled block, allocated with the first block.
+    compiled: Vec<bool>,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            compiled: Vec::new(),
+        }
+    }
+
+    /// Returns the block starting at start, compiling it again if the memory it was compiled from changed.
+    pub fn get_or_compile(&mut self, memory: &[u16], start: u16) -> Arc<Block> {
+        if let Some(block) = self.blocks.get(&start) {
+            let end = start as usize + block.words.len();
+            if memory.get(start as usize..end) == Some(&block.words[..]) {
+                return Arc::clone(block);
+            }
+        }
+        let block = Arc::new(compile_block(memory, start));
+        if self.compiled.is_empty() {
+            self.compiled = vec![false; memory.len()];
+        }
+        for index in 0..block.words.len() {
+            self.compiled[start as usize + index] = true;
+        }
+        self.blocks.insert(start, Arc::clone(&block));
+        block
+    }
+
+    /// Checks if address belongs to a compiled block.
+    pub fn contains(&self, address: u16) -> bool {
+        self.compiled
+            .get(address as usize)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.compiled.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::hardware::{ExecutionEngine, Register, TrapMode};
+    use crate::interrupts::TIMER_INTERRUPT;
+    use crate::lc3_vm::read_image_file;
+    use crate::snapshot::save_snapshot;
+
+    #[test]
+    fn blocks_end_at_the_first_jump() {
+        let program = assemble(
+            "
+            .ORIG x3000
+    LOOP    ADD R1, R1, #1
+            LEA R0, DATA
+            BRp LOOP
+            ADD R1, R1, #1
+    DATA    .FILL 0
+            .END",
+        )
+        .unwrap();
+        let mut vm = LC3VirtualMachine::new();
+        vm.registers[Register::PC] = 0x3000;
+        read_image_file(&mut vm, program.obj_image()).unwrap();
+        let mut cache = BlockCache::new();
+        let block = cache.get_or_compile(&vm.memory, 0x3000);
+        assert_eq!(block.words, vec![0x1261, 0xE002, 0x03FD]);
+        assert!(cache.contains(0x3002) && !cache.contains(0x3003));
+        // Changing the code without the vm compiles the block again.
+        vm.memory[0x3002] = 0x1261;
+        assert_eq!(cache.get_or_compile(&vm.memory, 0x3000).words.len(), 5);
+    }
+
+    /// Runs program on both engines with input, checking they end in the same state with the same output.
+    fn assert_same_behavior(program: &[u8], input: &[u8], setup: impl Fn(&mut LC3VirtualMachine)) {
+        let mut states = Vec::new();
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            let console = BufferConsole::new(input);
+            let output = console.output();
+            let mut vm = LC3VirtualMachine::builder()
+                .engine(engine)
+                .console(console)
+                .instruction_limit(300_000)
+                .image(program.to_vec())
+                .build()
+                .unwrap();
+            setup(&mut vm);
+            let result = vm.run();
+            let output = output.lock().unwrap().clone();
+            states.push((result, vm.instruction_count, save_snapshot(&vm), output));
+        }
+        assert_eq!(states[0].0, states[1].0);
+        assert_eq!(states[0].1, states[1].1);
+        assert!(states[0].2 == states[1].2, "the vm states differ");
+        assert_eq!(states[0].3, states[1].3);
+    }
+
+    #[test]
+    fn both_engines_run_the_example_games_the_same() {
+        let game = std::fs::read("example_images/2048.obj").unwrap();
+        assert_same_behavior(&game, &b"wasd".repeat(10), |_| ());
+        let game = std::fs::read("example_images/rogue.obj").unwrap();
+        assert_same_behavior(&game, b"yddddssss", |_| ());
+    }
+
+    #[test]
+    fn both_engines_handle_self_modifying_code() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            AND R1, R1, #0
+            AND R2, R2, #0
+            ADD R2, R2, #2
+    LOOP    ADD R1, R1, #1
+            LD R3, PATCH
+            ST R3, LOOP
+            ADD R2, R2, #-1
+            BRp LOOP
+            LD R0, CHAR
+            OUT
+            LDI R5, KBSR
+            HALT
+    PATCH   ADD R1, R1, #5
+    CHAR    .FILL x0041
+    KBSR    .FILL xFE00
+            .END",
+        )
+        .unwrap()
+        .obj_image();
+        assert_same_behavior(&program, b"k", |_| ());
+        assert_same_behavior(&program, b"k", |vm| vm.set_trap_mode(TrapMode::Native));
+    }
+
+    #[test]
+    fn both_engines_service_interrupts_at_the_same_instructions() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R6, STACK
+            LD R0, HANDLER_ADDRESS
+            STI R0, TIMER_VECTOR
+            AND R0, R0, #0
+            ADD R0, R0, #7
+            STI R0, TIR
+            LD R0, ENABLE
+            STI R0, TSR
+            LD R1, COUNT
+    LOOP    ADD R2, R2, #1
+            ADD R1, R1, #-1
+            BRp LOOP
+            AND R0, R0, #0
+            STI R0, TSR
+            ADD R4, R2, R3
+            HALT
+    HANDLER LDI R5, TSR
+            ADD R3, R3, #1
+            RTI
+    HANDLER_ADDRESS .FILL HANDLER
+    TIMER_VECTOR    .FILL x0182
+    TIR     .FILL xFE0A
+    TSR     .FILL xFE08
+    ENABLE  .FILL x4000
+    COUNT   .FILL #200
+    STACK   .FILL x4000
+            .END",
+        )
+        .unwrap();
+        let handler = program
+            .symbols
+            .iter()
+            .find(|(label, _)| label == "HANDLER")
+            .unwrap()
+            .1;
+        let image = program.obj_image();
+        assert_same_behavior(&image, b"", |_| ());
+        // A request raised before running is serviced by both engines before the first instruction.
+        assert_same_behavior(&image, b"", |vm| {
+            vm.memory[0x0182] = handler;
+            vm.registers[Register::R6] = 0x4000;
+            vm.raise_interrupt(TIMER_INTERRUPT);
+        });
+    }
+}
@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use crate::console::ConsoleDevice;
+use crate::hardware::{ExecutionEngine, Privilege, Register, TrapMode};
+use crate::lc3_vm::{LC3VirtualMachine, VMError, read_image, read_image_file};
+use crate::os::os_image;
+
+/// Source of an image to be loaded into memory when the vm is built.
+enum ImageSource {
+    Path(String),
+    Bytes(Vec<u8>),
+}
+
+/// Builder for [`LC3VirtualMachine`].
+///
+/// By default the machine is built with origin 0x3000, the PC pointing to the origin and the Pos flag on, which is
+/// the state the vm needs to run a regular LC3 image. The program runs in user mode, so TRAP, exceptions and
+/// interrupts switch to the supervisor stack to save the PSR and the PC, and the R6 of the program is left alone.
+/// When it starts in supervisor mode instead, R6 points to the supervisor stack. If no console is attached the vm
+/// uses stdin and stdout.
+/// The trap routines run from the default OS image, loaded before the other images, unless native traps are chosen.
+pub struct LC3VirtualMachineBuilder {
+    origin: u16,
+    pc: Option<u16>,
+    cond: u16,
+    privilege: Privilege,
+    saved_ssp: u16,
+    images: Vec<ImageSource>,
+    console: Option<Box<dyn ConsoleDevice>>,
+    trap_mode: TrapMode,
+    os_image: Option<Vec<u8>>,
+    instruction_limit: Option<u64>,
+    timeout: Option<Duration>,
+    decode_cache: bool,
+    engine: ExecutionEngine,
+}
+
+impl Default for LC3VirtualMachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LC3VirtualMachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            origin: 0x3000,
+            pc: None,
+            cond: 1, // Flag Pos
+            privilege: Privilege::User,
+            saved_ssp: 0x3000,
+            images: Vec::new(),
+            console: None,
+            trap_mode: TrapMode::default(),
+            os_image: None,
+            instruction_limit: None,
+            timeout: None,
+            decode_cache: true,
+            engine: ExecutionEngine::Interpreter,
+        }
+    }
+
+    /// Sets the origin address, which is the lowest address where images can be loaded.
+    pub fn origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Sets the initial value of the PC. If it's not set the PC starts at the origin.
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// Sets the initial value of the COND register (0b001 Pos, 0b010 Zro, 0b100 Neg).
+    pub fn cond(mut self, cond: u16) -> Self {
+        self.cond = cond;
+        self
+    }
+
+    /// Sets the privilege mode the program starts running in, user mode by default. Programs running in user mode
+    /// can't access system space or the device registers, but they can call the trap routines. Programs that poll
+    /// the devices themselves have to run in supervisor mode, where TRAP pushes the PSR and the PC onto their stack.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Sets the initial supervisor stack pointer, used by TRAP, exceptions and interrupts.
+    pub fn supervisor_stack(mut self, saved_ssp: u16) -> Self {
+        self.saved_ssp = saved_ssp;
+        self
+    }
+
+    /// Adds an image file to be loaded into memory. Images are loaded in the same order they were added.
+    pub fn image_path(mut self, path: &str) -> Self {
+        self.images.push(ImageSource::Path(String::from(path)));
+        self
+    }
+
+    /// Adds an image already read into a buffer to be loaded into memory. Images are loaded in the same order they
+    /// were added.
+    pub fn image(mut self, image: Vec<u8>) -> Self {
+        self.images.push(ImageSource::Bytes(image));
+        self
+    }
+
+    /// Attaches the console used by the trap routines and the keyboard device.
+    pub fn console(mut self, console: impl ConsoleDevice + 'static) -> Self {
+        self.console = Some(Box::new(console));
+        self
+    }
+
+    /// Sets how TRAP instructions run the service routines. With [`TrapMode::Native`] no OS image is loaded.
+    pub fn trap_mode(mut self, trap_mode: TrapMode) -> Self {
+        self.trap_mode = trap_mode;
+        self
+    }
+
+    /// Replaces the default OS image loaded in memory resident trap mode.
+    pub fn os_image(mut self, image: Vec<u8>) -> Self {
+        self.os_image = Some(image);
+        self
+    }
+
+    /// Sets the maximum number of instructions the vm executes before stopping with an error.
+    pub fn instruction_limit(mut self, limit: u64) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    /// Sets the maximum time a run can take before stopping with an error.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Enables or disables the cache of decoded instructions, enabled by default.
+    pub fn decode_cache(mut self, enabled: bool) -> Self {
+        self.decode_cache = enabled;
+        self
+    }
+
+    /// Sets how the program is executed, by default in the interpreter.
+    pub fn engine(mut self, engine: ExecutionEngine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Builds the vm loading all the images into memory.
+    pub fn build(self) -> Result<LC3VirtualMachine, VMError> {
+        let mut vm = LC3VirtualMachine::blank();
+        vm.origin = self.origin;
+        vm.registers[Register::PC] = self.pc.unwrap_or(self.origin);
+        vm.registers[Register::COND] = self.cond;
+        if let Some(console) = self.console {
+            vm.set_console(console);
+        }
+        vm.set_trap_mode(self.trap_mode);
+        vm.set_instruction_limit(self.instruction_limit);
+        vm.set_timeout(self.timeout);
+        vm.set_decode_cache(self.decode_cache);
+        vm.set_engine(self.engine);
+        if self.trap_mode == TrapMode::MemoryResident {
+            read_image_file(&mut vm, self.os_image.unwrap_or_else(os_image))?;
+        }
+        for image in self.images {
+            match image {
+                ImageSource::Path(path) => read_image(&mut vm, &path)?,
+                ImageSource::Bytes(buffer) => read_image_file(&mut vm, buffer)?,
+            }
+        }
+        // Images are loaded before switching the privilege, so they can be loaded in system space.
+        vm.saved_ssp = self.saved_ssp;
+        vm.set_privilege(self.privilege);
+        if self.privilege == Privilege::Supervisor {
+            vm.registers[Register::R6] = self.saved_ssp;
+        }
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn default_builder_sets_pc_on_origin_and_pos_flag() {
+        let vm = LC3VirtualMachineBuilder::new().build().unwrap();
+        assert_eq!(vm.origin, 0x3000);
+        assert_eq!(vm.registers[Register::PC], 0x3000);
+        assert_eq!(vm.registers[Register::COND], 1);
+        assert_eq!(vm.privilege(), Privilege::User);
+    }
+
+    #[test]
+    fn builder_loads_images_and_initial_registers() {
+        // Image loads ADD r0, r1, r2 and TRAP HALT at address 0x3000.
+        let vm = LC3VirtualMachineBuilder::new()
+            .pc(0x3001)
+            .cond(2)
+            .image(vec![0x30, 0x00, 0b00010000, 0b01000010, 0xF0, 0x25])
+            .build()
+            .unwrap();
+        assert_eq!(vm.registers[Register::PC], 0x3001);
+        assert_eq!(vm.registers[Register::COND], 2);
+        assert_eq!(vm.memory[0x3000], 0b0001000001000010);
+        assert_eq!(vm.memory[0x3001], 0xF025);
+    }
+
+    #[test]
+    fn builder_attaches_console() {
+        // Image loads TRAP GETC, TRAP OUT and TRAP HALT at address 0x3000.
+        let console = BufferConsole::new(b"z");
+        let output = console.output();
+        let mut vm = LC3VirtualMachineBuilder::new()
+            .console(console)
+            .image(vec![0x30, 0x00, 0xF0, 0x20, 0xF0, 0x21, 0xF0, 0x25])
+            .build()
+            .unwrap();
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(*output.lock().unwrap(), b"z");
+    }
+
+    #[test]
+    fn builder_with_native_traps_skips_os() {
+        let vm = LC3VirtualMachineBuilder::new()
+            .trap_mode(TrapMode::Native)
+            .build()
+            .unwrap();
+        assert_eq!(vm.trap_mode(), TrapMode::Native);
+        assert_eq!(vm.memory[0x25], 0);
+
+        let vm = LC3VirtualMachineBuilder::new()
+            .os_image(vec![0x00, 0x25, 0x04, 0x00])
+            .build()
+            .unwrap();
+        assert_eq!(vm.memory[0x25], 0x0400);
+        assert_eq!(vm.memory[0x20], 0);
+    }
+
+    #[test]
+    fn builder_sets_privilege_after_loading_images() {
+        let vm = LC3VirtualMachineBuilder::new()
+            .privilege(Privilege::User)
+            .supervisor_stack(0x2000)
+            .image(vec![0x02, 0x00, 0x12, 0x34])
+            .build()
+            .unwrap();
+        assert_eq!(vm.memory[0x0200], 0x1234);
+        assert_eq!(vm.privilege(), Privilege::User);
+        assert_eq!(vm.saved_ssp, 0x2000);
+    }
+
+    #[test]
+    fn user_programs_call_the_memory_resident_traps() {
+        // Image loads TRAP GETC, TRAP OUT and TRAP HALT at address 0x3000.
+        let console = BufferConsole::new(b"u");
+        let output = console.output();
+        let mut vm = LC3VirtualMachineBuilder::new()
+            .privilege(Privilege::User)
+            .supervisor_stack(0x2000)
+            .console(console)
+            .image(vec![0x30, 0x00, 0xF0, 0x20, 0xF0, 0x21, 0xF0, 0x25])
+            .build()
+            .unwrap();
+        vm.registers[Register::R6] = 0xF000;
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(*output.lock().unwrap(), b"u");
+        // HALT stopped the vm in the routine, which runs on the supervisor stack.
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+        assert_eq!(vm.saved_usp, 0xF000);
+        assert_eq!(vm.registers[Register::R6], 0x1FFE);
+        assert_eq!(vm.memory[0x1FFE], 0x3003);
+    }
+
+    #[test]
+    fn traps_leave_the_stack_of_the_program_alone() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R0, FIRST
+            LEA R6, TOP
+            OUT
+            LDR R0, R6, #-1
+            OUT
+            HALT
+    FIRST   .FILL x41
+    SECOND  .FILL x42
+    TOP     .FILL x0000
+            .END",
+        )
+        .unwrap();
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut vm = LC3VirtualMachineBuilder::new()
+            .console(console)
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(*output.lock().unwrap(), b"AB");
+        assert_eq!(vm.saved_usp, 0x3008);
+    }
+
+    #[test]
+    fn builder_fails_with_missing_image_file() {
+        let result = LC3VirtualMachineBuilder::new()
+            .image_path("this_image_does_not_exist.obj")
+            .build();
+        assert!(matches!(result, Err(VMError::FailedToLoadImage(_))));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::symbols::SymbolTable;
+
+/// Number of calls kept in the call stack. Once it's full the oldest calls are forgotten, so programs that call
+/// subroutines and never return from them don't grow it forever.
+pub const MAX_CALL_DEPTH: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CallKind {
+    Jsr,           /* JSR with a PC relative offset */
+    Jsrr,          /* JSRR with the address in a register */
+    Trap(u8),      /* TRAP run by a routine in memory, with its trap vector */
+    Interrupt(u8), /* interrupt or exception, with its vector in the interrupt vector table */
+}
+
+/// Call that hasn't returned yet.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the call instruction, or of the instruction interrupted or that caused the exception.
+    pub call_site: u16,
+    /// Address of the subroutine or trap routine called.
+    pub target: u16,
+    pub return_address: u16,
+}
+
+/// Shadow call stack built from the JSR, JSRR and TRAP instructions executed and the RET and RTI instructions that
+/// return from them. The stack of the program itself isn't used, since LC3 programs save R7 wherever they want.
+///
+/// Interrupts and exceptions are recorded as calls too, so the calls of the interrupted code stay apart from the ones
+/// of the service routine. RET goes back from the innermost call of the running routine with its return address,
+/// forgetting any call made after it, and RTI from the innermost interrupt or trap routine. A return to an address no
+/// call returns to is ignored.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct CallStack {
+    frames: VecDeque<CallFrame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Calls that haven't returned, the outermost first.
+    pub fn frames(&self) -> &VecDeque<CallFrame> {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Records the execution of word at pc, which left next_pc in the PC.
+    #[inline]
+    pub(crate) fn record(&mut self, pc: u16, word: u16, next_pc: u16) {
+        let kind = match word >> 12 {
+            0b0100 if word & (1 << 11) != 0 => CallKind::Jsr,
+            0b0100 => CallKind::Jsrr,
+            // Native traps don't jump anywhere, so they aren't calls.
+            0b1111 if next_pc != pc.wrapping_add(1) => CallKind::Trap(word as u8),
+            0b1100 if (word >> 6) & 0b111 == 7 => return self.return_to(next_pc),
+            0b1000 => return self.return_from_interrupt(next_pc),
+            _ => return,
+        };
+        self.push(CallFrame {
+            kind,
+            call_site: pc,
+            target: next_pc,
+            return_address: pc.wrapping_add(1),
+        });
+    }
+
+    /// Records the start of the service routine at target of an interrupt or exception with vector, raised at
+    /// call_site, which RTI returns from to return_address.
+    pub(crate) fn record_interrupt(
+        &mut self,
+        vector: u8,
+        call_site: u16,
+        target: u16,
+        return_address: u16,
+    ) {
+        self.push(CallFrame {
+            kind: CallKind::Interrupt(vector),
+            call_site,
+            target,
+            return_address,
+        });
+    }
+
+    fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() == MAX_CALL_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Returns from the innermost call of the running service routine that returns to return_address.
+    fn return_to(&mut self, return_address: u16) {
+        if let Some(index) = self
+            .frames
+            .iter()
+            .rev()
+            .take_while(|frame| !matches!(frame.kind, CallKind::Interrupt(_)))
+            .position(|frame| frame.return_address == return_address)
+        {
+            self.frames.truncate(self.frames.len() - 1 - index);
+        }
+    }
+
+    /// Returns from the innermost interrupt or trap routine in memory, if it returns to return_address. RTI doesn't
+    /// return when it raises an exception instead.
+    fn return_from_interrupt(&mut self, return_address: u16) {
+        if let Some(index) = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame.kind, CallKind::Interrupt(_) | CallKind::Trap(_)))
+            .filter(|index| self.frames[*index].return_address == return_address)
+        {
+            self.frames.truncate(index);
+        }
+    }
+
+    /// Returns the backtrace of the instruction at pc, with the addresses described by their labels.
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> Backtrace {
+        let location = |address: u16| symbols.locate(address).map(|_| symbols.describe(address));
+        let mut frames = vec![BacktraceFrame {
+            address: pc,
+            location: location(pc),
+            call: None,
+            target_location: None,
+        }];
+        frames.extend(self.frames.iter().rev().map(|call| BacktraceFrame {
+            address: call.call_site,
+            location: location(call.call_site),
+            call: Some(*call),
+            target_location: location(call.target),
+        }));
+        Backtrace { frames }
+    }
+}
+
+/// Frame of a backtrace.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BacktraceFrame {
+    /// Address of the instruction running in the innermost frame and of the call in the others.
+    pub address: u16,
+    /// Label of the address with the offset from it, if there's a label before it.
+    pub location: Option<String>,
+    /// Call made at the address, in every frame but the innermost.
+    pub call: Option<CallFrame>,
+    /// Label of the address called.
+    pub target_location: Option<String>,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x{:04X}", self.address)?;
+        if let Some(location) = &self.location {
+            write!(f, " in {}", location)?;
+        }
+        if let Some(call) = &self.call {
+            match call.kind {
+                CallKind::Jsr => write!(f, ": JSR to x{:04X}", call.target)?,
+                CallKind::Jsrr => write!(f, ": JSRR to x{:04X}", call.target)?,
+                CallKind::Trap(vector) => {
+                    write!(f, ": TRAP x{:02X} to x{:04X}", vector, call.target)?
+                }
+                CallKind::Interrupt(vector) => {
+                    write!(f, ": interrupt x{:02X} to x{:04X}", vector, call.target)?
+                }
+            }
+            if let Some(target_location) = &self.target_location {
+                write!(f, " ({})", target_location)?;
+            }
+            write!(f, ", returns to x{:04X}", call.return_address)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the program was running and the calls that led there, the innermost first.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, frame) in self.frames.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "#{} {}", index, frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::hardware::{ExecutionEngine, TrapMode};
+    use crate::lc3_vm::{LC3VirtualMachine, VMError};
+
+    /// Calls OUTER, which calls INNER, which runs a reserved instruction.
+    const NESTED: &str = "
+            .ORIG x3000
+    MAIN    JSR OUTER
+            HALT
+    OUTER   ST R7, SAVED
+            LEA R1, INNER
+            JSRR R1
+            LD R7, SAVED
+            RET
+    INNER   AND R0, R0, #0
+            .FILL xD000
+            RET
+    SAVED   .BLKW 1
+            .END";
+
+    #[test]
+    fn backtrace_of_nested_calls() {
+        let program = assemble(NESTED).unwrap();
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(TrapMode::Native)
+                .engine(engine)
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            vm.set_symbols(SymbolTable::from_symbols(program.symbols.clone()));
+            assert!(matches!(vm.run(), Err(VMError::InvalidInstruction(_))));
+            assert_eq!(
+                vm.backtrace().to_string(),
+                "#0 x3008 in INNER+1\n\
+                 #1 x3004 in OUTER+2: JSRR to x3007 (INNER), returns to x3005\n\
+                 #2 x3000 in MAIN: JSR to x3002 (OUTER), returns to x3001"
+            );
+        }
+    }
+
+    #[test]
+    fn returns_pop_their_calls() {
+        let program = assemble(&NESTED.replace(".FILL xD000", "ADD R0, R0, #1")).unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        assert_eq!(Ok(()), vm.run());
+        // The OS HALT routine was called with TRAP and stopped the machine before returning.
+        let frames = vm.call_stack().frames();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].kind, CallKind::Trap(0x25));
+        assert_eq!(frames[0].call_site, 0x3001);
+    }
+
+    #[test]
+    fn backtrace_of_exception_routines() {
+        let source = NESTED.replace("SAVED   .BLKW 1", "SAVED   .BLKW 1\nHANDLER TRAP x30");
+        let program = assemble(&source).unwrap();
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            let mut vm = LC3VirtualMachine::builder()
+                .engine(engine)
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            vm.memory[0x0101] = 0x300B; // Illegal opcode service routine
+            vm.set_symbols(SymbolTable::from_symbols(program.symbols.clone()));
+            assert!(vm.run().is_err());
+            assert_eq!(
+                vm.backtrace().to_string(),
+                "#0 x300B in HANDLER\n\
+                 #1 x3008 in INNER+1: interrupt x01 to x300B (HANDLER), returns to x3009\n\
+                 #2 x3004 in OUTER+2: JSRR to x3007 (INNER), returns to x3005\n\
+                 #3 x3000 in MAIN: JSR to x3002 (OUTER), returns to x3001"
+            );
+        }
+    }
+
+    #[test]
+    fn returns_from_interrupts_keep_the_calls_of_the_interrupted_code() {
+        const JSR_SUB: u16 = 0x4FFE;
+        const RET: u16 = 0xC1C0;
+        const RTI: u16 = 0x8000;
+        let mut call_stack = CallStack::new();
+        // MAIN calls SUB, which calls itself twice from the same address and returns once.
+        call_stack.record(0x3000, 0x4802, 0x3003);
+        call_stack.record(0x3004, JSR_SUB, 0x3003);
+        call_stack.record(0x3004, JSR_SUB, 0x3003);
+        call_stack.record(0x3007, RET, 0x3005);
+        // An interrupt arrives at the return address of the outer call of SUB, and its routine returns with RET from
+        // a call of its own and then with RTI.
+        call_stack.record_interrupt(0x80, 0x3005, 0x1000, 0x3005);
+        call_stack.record(0x1000, 0x4801, 0x1002);
+        call_stack.record(0x1002, RET, 0x1001);
+        call_stack.record(0x1001, RTI, 0x3005);
+        let return_addresses: Vec<u16> = call_stack
+            .frames()
+            .iter()
+            .map(|frame| frame.return_address)
+            .collect();
+        assert_eq!(return_addresses, vec![0x3001, 0x3005]);
+    }
+
+    #[test]
+    fn oldest_calls_are_forgotten() {
+        let mut call_stack = CallStack::new();
+        for call in 0..MAX_CALL_DEPTH as u16 + 10 {
+            call_stack.record(call, 0x4800, call.wrapping_add(1));
+        }
+        assert_eq!(call_stack.frames().len(), MAX_CALL_DEPTH);
+        assert_eq!(call_stack.frames()[0].call_site, 10);
+    }
+}
@@ -0,0 +1,293 @@
+use console::Term;
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::lc3_vm::VMError;
+
+/// Console I/O backend used by the vm for the trap routines and the keyboard device.
+pub trait ConsoleDevice: Send {
+    /// Reads a byte, waiting until there's one available.
+    fn read_byte(&mut self) -> Result<u8, VMError>;
+
+    /// Returns the key being pressed, if there's any.
+    fn poll_key(&mut self) -> Result<Option<u8>, VMError>;
+
+    /// Writes a byte to the console output.
+    fn write_byte(&mut self, byte: u8) -> Result<(), VMError>;
+
+    /// Flushes the console output.
+    fn flush(&mut self) -> Result<(), VMError>;
+}
+
+/// Starts a background thread that reads input byte by byte and queues it in the returned channel. The channel is
+/// disconnected when the input ends.
+fn spawn_input_queue<R: Read + Send + 'static>(input: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in BufReader::new(input).bytes() {
+            match byte {
+                Ok(byte) => {
+                    if sender.send(byte).is_err() {
+                        break; // The console was dropped
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for the next byte in an input queue.
+fn receive_byte(input: &Receiver<u8>) -> Result<u8, VMError> {
+    input.recv().map_err(|_| VMError::EndOfInput)
+}
+
+/// Returns the next byte in an input queue without waiting, if there's any.
+fn try_receive_byte(input: &Receiver<u8>) -> Option<u8> {
+    match input.try_recv() {
+        Ok(byte) => Some(byte),
+        Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+    }
+}
+
+/// Console that reads from stdin and writes to stdout.
+/// Stdin is read by a background thread, started the first time the vm asks for input, so polling the keyboard
+/// returns immediately when no key was pressed.
+pub struct StdioConsole {
+    term: Term,
+    input: Option<Receiver<u8>>,
+}
+
+impl Default for StdioConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StdioConsole {
+    pub fn new() -> Self {
+        Self {
+            term: Term::stdout(),
+            input: None,
+        }
+    }
+
+    fn input(&mut self) -> &Receiver<u8> {
+        self.input
+            .get_or_insert_with(|| spawn_input_queue(io::stdin()))
+    }
+}
+
+impl ConsoleDevice for StdioConsole {
+    fn read_byte(&mut self) -> Result<u8, VMError> {
+        receive_byte(self.input())
+    }
+
+    fn poll_key(&mut self) -> Result<Option<u8>, VMError> {
+        Ok(try_receive_byte(self.input()))
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), VMError> {
+        self.term
+            .write_all(&[byte])
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+
+    fn flush(&mut self) -> Result<(), VMError> {
+        self.term
+            .flush()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+}
+
+/// Console that reads from an in-memory input buffer and captures the output in memory.
+/// The captured output can be accessed through the handle returned by [`BufferConsole::output`], even after the
+/// console was moved into the vm.
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns a handle to the output written to the console.
+    pub fn output(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.output)
+    }
+}
+
+impl ConsoleDevice for BufferConsole {
+    fn read_byte(&mut self) -> Result<u8, VMError> {
+        self.input.pop_front().ok_or(VMError::EndOfInput)
+    }
+
+    fn poll_key(&mut self) -> Result<Option<u8>, VMError> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), VMError> {
+        self.output
+            .lock()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))?
+            .push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VMError> {
+        Ok(())
+    }
+}
+
+/// Console for scripted runs, which reads from an input given in advance and writes the output to any writer.
+/// Once the whole input was consumed, polling the keyboard fails with [`VMError::EndOfInput`] like a read does, so a
+/// program waiting for a key stops instead of waiting forever.
+/// More input can be queued through the handle returned by [`ScriptedConsole::input`], even after the console was
+/// moved into the vm.
+pub struct ScriptedConsole<W: Write + Send> {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: W,
+}
+
+impl<W: Write + Send> ScriptedConsole<W> {
+    pub fn new(input: &[u8], output: W) -> Self {
+        Self {
+            input: Arc::new(Mutex::new(input.iter().copied().collect())),
+            output,
+        }
+    }
+
+    /// Returns a handle to the input that hasn't been read yet.
+    pub fn input(&self) -> Arc<Mutex<VecDeque<u8>>> {
+        Arc::clone(&self.input)
+    }
+}
+
+impl<W: Write + Send> ConsoleDevice for ScriptedConsole<W> {
+    fn read_byte(&mut self) -> Result<u8, VMError> {
+        self.input
+            .lock()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))?
+            .pop_front()
+            .ok_or(VMError::EndOfInput)
+    }
+
+    fn poll_key(&mut self) -> Result<Option<u8>, VMError> {
+        self.read_byte().map(Some)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), VMError> {
+        self.output
+            .write_all(&[byte])
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+
+    fn flush(&mut self) -> Result<(), VMError> {
+        self.output
+            .flush()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+}
+
+/// Console connected to a pair of pipes, such as the stdout and stdin of a child process, a socket or a file.
+/// Input is read by a background thread so polling the keyboard never blocks.
+pub struct PipeConsole<W: Write + Send> {
+    input: Receiver<u8>,
+    output: W,
+}
+
+impl<W: Write + Send> PipeConsole<W> {
+    pub fn new<R: Read + Send + 'static>(input: R, output: W) -> Self {
+        Self {
+            input: spawn_input_queue(input),
+            output,
+        }
+    }
+}
+
+impl<W: Write + Send> ConsoleDevice for PipeConsole<W> {
+    fn read_byte(&mut self) -> Result<u8, VMError> {
+        receive_byte(&self.input)
+    }
+
+    fn poll_key(&mut self) -> Result<Option<u8>, VMError> {
+        Ok(try_receive_byte(&self.input))
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), VMError> {
+        self.output
+            .write_all(&[byte])
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+
+    fn flush(&mut self) -> Result<(), VMError> {
+        self.output
+            .flush()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_console_reads_input_and_captures_output() {
+        let mut console = BufferConsole::new(b"ab");
+        let output = console.output();
+        assert_eq!(Ok(b'a'), console.read_byte());
+        assert_eq!(Ok(Some(b'b')), console.poll_key());
+        assert_eq!(Ok(None), console.poll_key());
+        assert!(console.read_byte().is_err());
+
+        assert_eq!(Ok(()), console.write_byte(b'o'));
+        assert_eq!(Ok(()), console.write_byte(b'k'));
+        assert_eq!(*output.lock().unwrap(), b"ok");
+    }
+
+    #[test]
+    fn pipe_console_reads_input_and_writes_output() {
+        let mut console = PipeConsole::new(io::Cursor::new(b"x".to_vec()), Vec::new());
+        assert_eq!(Ok(b'x'), console.read_byte());
+        assert!(console.read_byte().is_err());
+        assert_eq!(Ok(None), console.poll_key());
+
+        assert_eq!(Ok(()), console.write_byte(b'y'));
+        assert_eq!(Ok(()), console.flush());
+        assert_eq!(console.output, b"y");
+    }
+
+    #[test]
+    fn scripted_console_fails_polling_after_input() {
+        let mut console = ScriptedConsole::new(b"ab", Vec::new());
+        assert_eq!(Ok(Some(b'a')), console.poll_key());
+        assert_eq!(Ok(b'b'), console.read_byte());
+        assert_eq!(Err(VMError::EndOfInput), console.poll_key());
+        assert_eq!(Err(VMError::EndOfInput), console.read_byte());
+        console.input().lock().unwrap().push_back(b'c');
+        assert_eq!(Ok(Some(b'c')), console.poll_key());
+
+        assert_eq!(Ok(()), console.write_byte(b'z'));
+        assert_eq!(console.output, b"z");
+    }
+
+    #[test]
+    fn polling_without_input_returns_immediately() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let mut console = PipeConsole::new(reader, Vec::new());
+        assert_eq!(Ok(None), console.poll_key());
+
+        writer.write_all(b"k").unwrap();
+        assert_eq!(Ok(b'k'), console.read_byte());
+        assert_eq!(Ok(None), console.poll_key());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::symbols::DebugInfo;
+
+/// Times a conditional branch was taken and not taken.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Checks if word is a branch that can go either way, so BRnzp and the branch that never jumps aren't.
+fn is_conditional_branch(word: u16) -> bool {
+    let flags = (word >> 9) & 0b111;
+    word >> 12 == 0 && flags != 0 && flags != 0b111
+}
+
+/// Records which addresses are executed and which way every conditional branch goes.
+///
+/// Coverage of several runs is added up by attaching the same coverage to every vm, one after the other.
+pub struct Coverage {
+    hits: Vec<u64>,
+    branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; 1 << 16],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Records the execution of word at pc, which left next_pc in the PC.
+    #[inline]
+    pub(crate) fn record(&mut self, pc: u16, word: u16, next_pc: u16) {
+        self.hits[pc as usize] += 1;
+        if is_conditional_branch(word) {
+            let branch = self.branches.entry(pc).or_default();
+            if next_pc == pc.wrapping_add(1) {
+                branch.not_taken += 1;
+            } else {
+                branch.taken += 1;
+            }
+        }
+    }
+
+    /// Number of times the instruction at address was executed.
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits[address as usize]
+    }
+
+    /// Which way the branch at address went, if it was executed.
+    pub fn branch(&self, address: u16) -> Option<BranchCoverage> {
+        self.branches.get(&address).copied()
+    }
+
+    /// Maps the coverage to the source lines in debug_info. memory holds the program, to find the conditional
+    /// branches that never ran.
+    pub fn report(&self, debug_info: &DebugInfo, memory: &[u16]) -> CoverageReport {
+        let mut lines: Vec<LineCoverage> = debug_info
+            .lines
+            .iter()
+            .map(|(address, line)| LineCoverage {
+                line: *line,
+                hits: self.hits(*address),
+                branch: memory
+                    .get(*address as usize)
+                    .is_some_and(|word| is_conditional_branch(*word))
+                    .then(|| self.branch(*address).unwrap_or_default()),
+            })
+            .collect();
+        lines.sort_by_key(|line| line.line);
+        CoverageReport {
+            source: debug_info.source.clone(),
+            lines,
+        }
+    }
+}
+
+/// Coverage of a source line with an instruction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LineCoverage {
+    pub line: usize,
+    pub hits: u64,
+    /// Which way the instruction went, if it's a conditional branch.
+    pub branch: Option<BranchCoverage>,
+}
+
+/// Coverage of the instructions of a source file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CoverageReport {
+    pub source: String,
+    pub lines: Vec<LineCoverage>,
+}
+
+impl CoverageReport {
+    /// Number of lines executed at least once.
+    pub fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|line| line.hits > 0).count()
+    }
+
+    /// Number of branch directions, two per conditional branch.
+    pub fn branches_found(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.branch.is_some())
+            .count()
+            * 2
+    }
+
+    /// Number of branch directions taken at least once.
+    pub fn branches_hit(&self) -> usize {
+        self.lines
+            .iter()
+            .filter_map(|line| line.branch)
+            .map(|branch| (branch.taken > 0) as usize + (branch.not_taken > 0) as usize)
+            .sum()
+    }
+
+    /// Returns the report in the lcov tracefile format, which tools like genhtml turn into HTML.
+    pub fn lcov(&self) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", self.source);
+        for line in &self.lines {
+            if let Some(branch) = line.branch {
+                let count = |count: u64| match line.hits {
+                    0 => String::from("-"),
+                    _ => count.to_string(),
+                };
+                lcov.push_str(&format!(
+                    "BRDA:{},0,0,{}\nBRDA:{},0,1,{}\n",
+                    line.line,
+                    count(branch.taken),
+                    line.line,
+                    count(branch.not_taken)
+                ));
+            }
+        }
+        lcov.push_str(&format!(
+            "BRF:{}\nBRH:{}\n",
+            self.branches_found(),
+            self.branches_hit()
+        ));
+        for line in &self.lines {
+            lcov.push_str(&format!("DA:{},{}\n", line.line, line.hits));
+        }
+        lcov.push_str(&format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            self.lines.len(),
+            self.lines_hit()
+        ));
+        lcov
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} lines and {} of {} branches covered",
+            self.source,
+            self.lines_hit(),
+            self.lines.len(),
+            self.branches_hit(),
+            self.branches_found()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::hardware::{ExecutionEngine, TrapMode};
+    use crate::lc3_vm::LC3VirtualMachine;
+
+    const SIGN: &str = "
+            .ORIG x3000
+            LD R0, VALUE
+            BRn NEGATIVE
+            AND R1, R1, #0
+            HALT
+    NEGATIVE
+            ADD R1, R1, #-1
+            HALT
+    VALUE   .FILL #5
+            .END";
+
+    #[test]
+    fn lcov_report_of_every_line_and_branch() {
+        let program = assemble(SIGN).unwrap();
+        let debug_info = DebugInfo::parse(&program.debug_file("sign.asm")).unwrap();
+        let mut coverage = Coverage::new();
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(TrapMode::Native)
+                .engine(engine)
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            vm.set_coverage(Some(coverage));
+            assert_eq!(Ok(()), vm.run());
+            coverage = vm.take_coverage().unwrap();
+        }
+        let mut vm = LC3VirtualMachine::new();
+        crate::lc3_vm::read_image_file(&mut vm, program.obj_image()).unwrap();
+        let report = coverage.report(&debug_info, &vm.memory);
+        assert_eq!(
+            report.lcov(),
+            "TN:\nSF:sign.asm\nBRDA:4,0,0,0\nBRDA:4,0,1,2\nBRF:2\nBRH:1\n\
+             DA:3,2\nDA:4,2\nDA:5,2\nDA:6,2\nDA:8,0\nDA:9,0\nLF:6\nLH:4\nend_of_record\n"
+        );
+        assert_eq!(
+            report.to_string(),
+            "sign.asm: 4 of 6 lines and 1 of 2 branches covered"
+        );
+    }
+}
@@ -0,0 +1,666 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::assembler::parse_number;
+use crate::disassembler::disassemble_instruction;
+use crate::hardware::Register;
+use crate::lc3_vm::{LC3VirtualMachine, VMError};
+use crate::snapshot::{load_snapshot, save_snapshot};
+
+const HELP: &str = "Commands:
+  step [n]            (s) execute n instructions, 1 by default
+  next                (n) execute one instruction, running subroutines and traps until they return
+  continue            (c) run until a breakpoint is reached or the program halts
+  break ADDR          (b) set a breakpoint at ADDR
+  delete ADDR         (d) remove the breakpoint at ADDR
+  breakpoints             list the breakpoints
+  registers           (r) print the registers
+  set REG VALUE           set a register (R0-R7, PC, COND) to VALUE
+  memory ADDR [n]     (x) print n words of memory starting at ADDR, 1 by default
+  write ADDR VALUE    (w) write VALUE in memory at ADDR, with the side effects of writing a device register
+  input TEXT          (i) queue TEXT as keyboard input for the program, with \\n for a new line
+  list [ADDR] [n]     (l) disassemble n instructions starting at ADDR, by default 10 starting at the PC
+  backtrace          (bt) print the calls that led to the PC
+  help                (h) print this help
+  quit                (q) exit the debugger
+Numbers can be written as decimal (10 or #10) or hexadecimal (xA or 0xA). An empty line repeats the last command.";
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum DebuggerCommand {
+    Step(usize),
+    Next,
+    Continue,
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Registers,
+    SetRegister(Register, u16),
+    Memory(u16, u16),
+    WriteMemory(u16, u16),
+    Input(String),
+    List(Option<u16>, u16),
+    Backtrace,
+    Help,
+    Quit,
+}
+
+fn parse_word(token: &str) -> Result<u16, String> {
+    match parse_number(token) {
+        Some(value) if (-0x8000..=0xFFFF).contains(&value) => Ok(value as u16),
+        _ => Err(format!("Invalid number: {}", token)),
+    }
+}
+
+fn parse_register(token: &str) -> Result<Register, String> {
+    let upper = token.to_uppercase();
+    let register_number = match upper.as_str() {
+        "PC" => 8,
+        "COND" => 9,
+        _ => upper
+            .strip_prefix('R')
+            .and_then(|number| number.parse::<u16>().ok())
+            .filter(|number| *number < 8)
+            .ok_or(format!("Invalid register: {}", token))?,
+    };
+    Register::from_u16(register_number).map_err(|error| error.to_string())
+}
+
+/// Replaces the `\n` escapes of text with new lines and `\\` with a backslash.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        match (char, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(char),
+        }
+    }
+    unescaped
+}
+
+impl DebuggerCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, arguments)) = tokens.split_first() else {
+            return Err(String::from("Empty command"));
+        };
+        match (*command, arguments) {
+            ("step" | "s", []) => Ok(Self::Step(1)),
+            ("step" | "s", [count]) => Ok(Self::Step(parse_word(count)? as usize)),
+            ("next" | "n", []) => Ok(Self::Next),
+            ("continue" | "c", []) => Ok(Self::Continue),
+            ("break" | "b", [address]) => Ok(Self::Break(parse_word(address)?)),
+            ("delete" | "d", [address]) => Ok(Self::Delete(parse_word(address)?)),
+            ("breakpoints", []) => Ok(Self::Breakpoints),
+            ("registers" | "r", []) => Ok(Self::Registers),
+            ("set", [register, value]) => Ok(Self::SetRegister(
+                parse_register(register)?,
+                parse_word(value)?,
+            )),
+            ("memory" | "x", [address]) => Ok(Self::Memory(parse_word(address)?, 1)),
+            ("memory" | "x", [address, count]) => {
+                Ok(Self::Memory(parse_word(address)?, parse_word(count)?))
+            }
+            ("write" | "w", [address, value]) => {
+                Ok(Self::WriteMemory(parse_word(address)?, parse_word(value)?))
+            }
+            ("input" | "i", [_, ..]) => {
+                // The text is taken verbatim from the line, spaces included.
+                let text = line.trim_start()[command.len()..].trim_start();
+                Ok(Self::Input(unescape(text)))
+            }
+            ("list" | "l", []) => Ok(Self::List(None, 10)),
+            ("list" | "l", [address]) => Ok(Self::List(Some(parse_word(address)?), 10)),
+            ("list" | "l", [address, count]) => {
+                Ok(Self::List(Some(parse_word(address)?), parse_word(count)?))
+            }
+            ("backtrace" | "bt", []) => Ok(Self::Backtrace),
+            ("help" | "h", []) => Ok(Self::Help),
+            ("quit" | "q", []) => Ok(Self::Quit),
+            _ => Err(format!(
+                "Invalid command: {}. Type help to see the commands",
+                line.trim()
+            )),
+        }
+    }
+}
+
+/// Reason why the vm stopped running under the debugger.
+#[derive(PartialEq, Debug)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(u16),
+    Halted,
+    /// The program needs a key and the input queued for it ran out. The step that needed it is undone, so the
+    /// program continues from the instruction that reads the key once there's more input.
+    WaitingForInput,
+}
+
+/// Interactive debugger that executes a vm instruction by instruction.
+///
+/// The debugger doesn't share its own input with the program. The program reads the input queued with the input
+/// command, when the vm has a [`crate::ScriptedConsole`] whose input handle was given to [`Debugger::set_input`].
+pub struct Debugger<'a> {
+    vm: &'a mut LC3VirtualMachine,
+    breakpoints: BTreeSet<u16>,
+    input: Option<Arc<Mutex<VecDeque<u8>>>>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut LC3VirtualMachine) -> Self {
+        vm.running = true;
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+            input: None,
+        }
+    }
+
+    /// Sets the input queue of the program, like the one returned by [`crate::ScriptedConsole::input`]. Once the
+    /// program runs out of input it stops with [`StopReason::WaitingForInput`] instead of failing.
+    pub fn set_input(&mut self, input: Arc<Mutex<VecDeque<u8>>>) {
+        self.input = Some(input);
+    }
+
+    /// Returns the vm being debugged.
+    pub fn vm(&mut self) -> &mut LC3VirtualMachine {
+        self.vm
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Executes instructions until the PC reaches stop_at, a breakpoint is reached, the program halts, runs out of
+    /// input or exceeds the instruction limit. At least one instruction is always executed, so the vm can continue
+    /// from a breakpoint.
+    fn resume(&mut self, stop_at: Option<u16>) -> Result<StopReason, VMError> {
+        loop {
+            self.vm.check_limits(None)?;
+            // Only a step with no input queued can run out of it, so the state is only saved then.
+            let before = self.input_is_empty().then(|| save_snapshot(self.vm));
+            let result = self.vm.step();
+            if let (Err(VMError::EndOfInput), Some(before)) = (&result, before) {
+                // The step is undone, interrupt and instruction count included, so the instruction that needed the
+                // key runs again once there's input.
+                load_snapshot(self.vm, &before)?;
+                return Ok(StopReason::WaitingForInput);
+            }
+            result?;
+            let pc = self.vm.registers[Register::PC];
+            if !self.vm.running {
+                return Ok(StopReason::Halted);
+            }
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            if stop_at.is_none_or(|address| address == pc) {
+                return Ok(StopReason::Stepped);
+            }
+        }
+    }
+
+    /// Checks if the program has an input queue and it's empty.
+    fn input_is_empty(&self) -> bool {
+        self.input
+            .as_ref()
+            .is_some_and(|input| input.lock().map_or(true, |input| input.is_empty()))
+    }
+
+    /// Executes count instructions, stopping before if a breakpoint is reached or the program halts.
+    pub fn step(&mut self, count: usize) -> Result<StopReason, VMError> {
+        let mut reason = StopReason::Stepped;
+        for _ in 0..count {
+            reason = self.resume(None)?;
+            if reason != StopReason::Stepped {
+                break;
+            }
+        }
+        Ok(reason)
+    }
+
+    /// Executes one instruction. If it's a subroutine call or a trap, the vm runs until it returns.
+    pub fn step_over(&mut self) -> Result<StopReason, VMError> {
+        let pc = self.vm.registers[Register::PC];
+        let op_code = self.vm.memory[pc as usize] >> 12;
+        if op_code == 0b0100 || op_code == 0b1111 {
+            // JSR, JSRR and TRAP return to the next instruction.
+            self.resume(Some(pc.wrapping_add(1)))
+        } else {
+            self.resume(None)
+        }
+    }
+
+    /// Runs until a breakpoint is reached or the program halts.
+    pub fn continue_execution(&mut self) -> Result<StopReason, VMError> {
+        loop {
+            match self.resume(None)? {
+                StopReason::Stepped => continue,
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    /// Executes a command writing its result to output. Returns false when the debugger has to finish.
+    pub fn execute(
+        &mut self,
+        command: &DebuggerCommand,
+        output: &mut impl Write,
+    ) -> Result<bool, VMError> {
+        match *command {
+            DebuggerCommand::Step(count) => {
+                let result = self.step(count);
+                self.report(result, output)?;
+            }
+            DebuggerCommand::Next => {
+                let result = self.step_over();
+                self.report(result, output)?;
+            }
+            DebuggerCommand::Continue => {
+                let result = self.continue_execution();
+                self.report(result, output)?;
+            }
+            DebuggerCommand::Break(address) => {
+                self.add_breakpoint(address);
+                write_line(output, &format!("Breakpoint set at x{:04X}", address))?;
+            }
+            DebuggerCommand::Delete(address) => {
+                if self.remove_breakpoint(address) {
+                    write_line(output, &format!("Breakpoint at x{:04X} deleted", address))?;
+                } else {
+                    write_line(
+                        output,
+                        &format!("There's no breakpoint at x{:04X}", address),
+                    )?;
+                }
+            }
+            DebuggerCommand::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    write_line(output, "No breakpoints")?;
+                }
+                for address in &self.breakpoints {
+                    write_line(output, &format!("x{:04X}", address))?;
+                }
+            }
+            DebuggerCommand::Registers => self.print_registers(output)?,
+            DebuggerCommand::SetRegister(register, value) => {
+                self.vm.registers[register] = value;
+                write_line(output, &format!("{} = x{:04X}", register, value))?;
+            }
+            DebuggerCommand::Memory(address, count) => {
+                for offset in 0..count {
+                    let address = address.wrapping_add(offset);
+                    write_line(
+                        output,
+                        &format!(
+                            "x{:04X}: x{:04X}",
+                            address, self.vm.memory[address as usize]
+                        ),
+                    )?;
+                }
+            }
+            DebuggerCommand::WriteMemory(address, value) => {
+                self.vm.write_word(address, value)?;
+                write_line(output, &format!("x{:04X}: x{:04X}", address, value))?;
+            }
+            DebuggerCommand::Input(ref text) => match &self.input {
+                Some(input) => {
+                    input
+                        .lock()
+                        .map_err(|error| VMError::IOError(format!("{:?}", error)))?
+                        .extend(text.bytes());
+                    write_line(output, "Input queued")?;
+                }
+                None => write_line(
+                    output,
+                    "The program doesn't read its input from the debugger",
+                )?,
+            },
+            DebuggerCommand::List(address, count) => {
+                let start = address.unwrap_or(self.vm.registers[Register::PC]);
+                for offset in 0..count {
+                    self.print_instruction(start.wrapping_add(offset), output)?;
+                }
+            }
+            DebuggerCommand::Backtrace => self.print_backtrace(output)?,
+            DebuggerCommand::Help => write_line(output, HELP)?,
+            DebuggerCommand::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Reads commands from input until the quit command or the end of the input.
+    pub fn repl(&mut self, input: impl BufRead, output: &mut impl Write) -> Result<(), VMError> {
+        self.print_instruction(self.vm.registers[Register::PC], output)?;
+        let mut last_command = None;
+        let mut lines = input.lines();
+        loop {
+            write_prompt(output)?;
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            let line = line.map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+            let command = if line.trim().is_empty() {
+                match last_command.clone() {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                match DebuggerCommand::parse(&line) {
+                    Ok(command) => command,
+                    Err(message) => {
+                        write_line(output, &message)?;
+                        continue;
+                    }
+                }
+            };
+            last_command = Some(command.clone());
+            if !self.execute(&command, output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Prints why the vm stopped and the next instruction to execute, with the backtrace when it stopped at a
+    /// breakpoint or failed.
+    fn report(
+        &mut self,
+        result: Result<StopReason, VMError>,
+        output: &mut impl Write,
+    ) -> Result<(), VMError> {
+        match result {
+            Ok(StopReason::Halted) => write_line(output, "Program halted"),
+            Ok(StopReason::WaitingForInput) => {
+                write_line(
+                    output,
+                    "The program is waiting for input, queue it with input TEXT",
+                )?;
+                self.print_instruction(self.vm.registers[Register::PC], output)
+            }
+            Ok(StopReason::Breakpoint(address)) => {
+                write_line(output, &format!("Breakpoint reached at x{:04X}", address))?;
+                self.print_instruction(address, output)?;
+                self.print_backtrace(output)
+            }
+            Ok(StopReason::Stepped) => {
+                self.print_instruction(self.vm.registers[Register::PC], output)
+            }
+            Err(error) => {
+                write_line(output, &format!("Error: {}", error))?;
+                write_line(output, &self.vm.backtrace().to_string())
+            }
+        }
+    }
+
+    fn print_backtrace(&self, output: &mut impl Write) -> Result<(), VMError> {
+        let backtrace = self
+            .vm
+            .call_stack()
+            .backtrace(self.vm.registers[Register::PC], self.vm.symbols());
+        write_line(output, &backtrace.to_string())
+    }
+
+    fn print_instruction(&self, address: u16, output: &mut impl Write) -> Result<(), VMError> {
+        let word = self.vm.memory[address as usize];
+        let marker = if address == self.vm.registers[Register::PC] {
+            "=>"
+        } else {
+            "  "
+        };
+        write_line(
+            output,
+            &format!(
+                "{} x{:04X}: x{:04X}  {}",
+                marker,
+                address,
+                word,
+                disassemble_instruction(address, word)
+            ),
+        )
+    }
+
+    fn print_registers(&self, output: &mut impl Write) -> Result<(), VMError> {
+        let registers = &self.vm.registers;
+        for (register, value) in registers.iter().take(8).enumerate() {
+            write_line(
+                output,
+                &format!("R{}   x{:04X}  {}", register, value, *value as i16),
+            )?;
+        }
+        let cond = registers[Register::COND];
+        let flag = match cond {
+            1 => "p",
+            2 => "z",
+            4 => "n",
+            _ => "-",
+        };
+        write_line(output, &format!("PC   x{:04X}", registers[Register::PC]))?;
+        write_line(output, &format!("COND x{:04X}  {}", cond, flag))?;
+        write_line(output, &format!("PSR  x{:04X}", self.vm.psr()))
+    }
+}
+
+fn write_line(output: &mut impl Write, line: &str) -> Result<(), VMError> {
+    writeln!(output, "{}", line).map_err(|error| VMError::IOError(format!("{:?}", error)))
+}
+
+fn write_prompt(output: &mut impl Write) -> Result<(), VMError> {
+    write!(output, "(lc3) ")
+        .and_then(|_| output.flush())
+        .map_err(|error| VMError::IOError(format!("{:?}", error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::{BufferConsole, ScriptedConsole};
+    use crate::hardware::TrapMode;
+    use crate::lc3_vm::read_image_file;
+
+    fn vm_with_program(source: &str) -> LC3VirtualMachine {
+        let program = assemble(source).unwrap();
+        let mut vm = LC3VirtualMachine::new();
+        vm.set_trap_mode(TrapMode::Native);
+        vm.set_console(Box::new(BufferConsole::new(b"")));
+        read_image_file(&mut vm, program.obj_image()).unwrap();
+        vm.set_pc_with_origin();
+        vm
+    }
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        JSR SUB
+        ADD R0, R0, #1
+        HALT
+SUB     ADD R0, R0, #5
+        RET
+        .END
+    ";
+
+    #[test]
+    fn parsing_commands() {
+        assert_eq!(DebuggerCommand::parse("s"), Ok(DebuggerCommand::Step(1)));
+        assert_eq!(
+            DebuggerCommand::parse("step 3"),
+            Ok(DebuggerCommand::Step(3))
+        );
+        assert_eq!(
+            DebuggerCommand::parse("b x3004"),
+            Ok(DebuggerCommand::Break(0x3004))
+        );
+        assert_eq!(
+            DebuggerCommand::parse("set r3 #-1"),
+            Ok(DebuggerCommand::SetRegister(Register::R3, 0xFFFF))
+        );
+        assert_eq!(
+            DebuggerCommand::parse("x 0x3000 4"),
+            Ok(DebuggerCommand::Memory(0x3000, 4))
+        );
+        assert!(DebuggerCommand::parse("set R8 1").is_err());
+        assert!(DebuggerCommand::parse("jump").is_err());
+    }
+
+    #[test]
+    fn stepping_and_stepping_over_subroutines() {
+        let mut vm = vm_with_program(PROGRAM);
+        let mut debugger = Debugger::new(&mut vm);
+        assert_eq!(Ok(StopReason::Stepped), debugger.step(2));
+        assert_eq!(debugger.vm.registers[Register::PC], 0x3004);
+
+        let mut vm = vm_with_program(PROGRAM);
+        let mut debugger = Debugger::new(&mut vm);
+        assert_eq!(Ok(StopReason::Stepped), debugger.step(1));
+        assert_eq!(Ok(StopReason::Stepped), debugger.step_over());
+        assert_eq!(debugger.vm.registers[Register::PC], 0x3002);
+        assert_eq!(debugger.vm.registers[Register::R0], 5);
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints_and_halt() {
+        let mut vm = vm_with_program(PROGRAM);
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.add_breakpoint(0x3005);
+        assert_eq!(
+            Ok(StopReason::Breakpoint(0x3005)),
+            debugger.continue_execution()
+        );
+        assert_eq!(debugger.vm.registers[Register::R0], 5);
+        assert_eq!(Ok(StopReason::Halted), debugger.continue_execution());
+        assert_eq!(vm.registers[Register::R0], 6);
+    }
+
+    #[test]
+    fn repl_runs_commands_from_input() {
+        let mut vm = vm_with_program(PROGRAM);
+        let mut debugger = Debugger::new(&mut vm);
+        let input = "b x3002\nc\nset R0 x10\nw x4000 7\nx x4000\nc\nr\nq\nc\n";
+        let mut output = Vec::new();
+        assert_eq!(Ok(()), debugger.repl(input.as_bytes(), &mut output));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("=> x3000: x5020  AND R0, R0, #0\n"));
+        assert!(output.contains("Breakpoint reached at x3002\n=> x3002: x1021  ADD R0, R0, #1\n"));
+        assert!(output.contains("x4000: x0007\n"));
+        assert!(output.contains("Program halted\n"));
+        assert!(output.contains("R0   x0011  17\n"));
+        assert_eq!(vm.memory[0x4000], 7);
+    }
+
+    #[test]
+    fn breakpoints_print_the_backtrace() {
+        let mut vm = vm_with_program(PROGRAM);
+        vm.set_symbols(crate::symbols::SymbolTable::from_symbols(
+            assemble(PROGRAM).unwrap().symbols,
+        ));
+        let mut debugger = Debugger::new(&mut vm);
+        let mut output = Vec::new();
+        assert_eq!(
+            Ok(()),
+            debugger.repl("b x3005\nc\ns\nbt\n".as_bytes(), &mut output)
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(
+            "Breakpoint reached at x3005\n=> x3005: xC1C0  RET\n#0 x3005 in SUB+1\n\
+             #1 x3001: JSR to x3004 (SUB), returns to x3002\n"
+        ));
+        assert!(output.ends_with("(lc3) #0 x3002\n(lc3) "));
+    }
+
+    #[test]
+    fn programs_read_the_input_queued_in_the_debugger() {
+        let program = assemble(".ORIG x3000\nGETC\nST R0, KEY\nHALT\nKEY .BLKW 1\n.END").unwrap();
+        for trap_mode in [TrapMode::Native, TrapMode::MemoryResident] {
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(trap_mode)
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            let console = ScriptedConsole::new(b"", Vec::new());
+            let input = console.input();
+            vm.set_console(Box::new(console));
+            let mut debugger = Debugger::new(&mut vm);
+            debugger.set_input(input);
+            let mut output = Vec::new();
+            assert_eq!(
+                Ok(()),
+                debugger.repl("c\ni k\nc\nx x3003\n".as_bytes(), &mut output)
+            );
+            let output = String::from_utf8(output).unwrap();
+            assert!(
+                output.contains("The program is waiting for input, queue it with input TEXT\n")
+            );
+            assert!(output.contains("Input queued\n"));
+            assert!(output.contains("Program halted\n"));
+            assert!(output.contains("x3003: x006B\n"));
+        }
+    }
+
+    #[test]
+    fn waiting_for_input_leaves_the_vm_as_before_the_step() {
+        let program = assemble(".ORIG x3000\nGETC\nHALT\n.END").unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        let console = ScriptedConsole::new(b"", Vec::new());
+        let input = console.input();
+        vm.set_console(Box::new(console));
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.set_input(input);
+        assert_eq!(
+            Ok(StopReason::WaitingForInput),
+            debugger.continue_execution()
+        );
+        let registers = debugger.vm().registers;
+        let psr = debugger.vm().psr();
+        let instruction_count = debugger.vm().instruction_count;
+        assert_eq!(
+            Ok(StopReason::WaitingForInput),
+            debugger.continue_execution()
+        );
+        assert_eq!(debugger.vm().registers, registers);
+        assert_eq!(debugger.vm().psr(), psr);
+        assert_eq!(debugger.vm().instruction_count, instruction_count);
+    }
+
+    #[test]
+    fn writing_memory_has_the_side_effects_of_the_devices() {
+        let mut vm = vm_with_program(PROGRAM);
+        let console = BufferConsole::new(b"");
+        let console_output = console.output();
+        vm.set_console(Box::new(console));
+        let mut debugger = Debugger::new(&mut vm);
+        let mut output = Vec::new();
+        assert_eq!(
+            Ok(()),
+            debugger.repl("w xFE06 x41\nw xFFFC x8002\n".as_bytes(), &mut output)
+        );
+        assert_eq!(*console_output.lock().unwrap(), b"A");
+        assert_eq!(vm.privilege(), crate::hardware::Privilege::User);
+        assert_eq!(vm.registers[Register::COND], 2);
+    }
+
+    #[test]
+    fn continue_stops_at_the_instruction_limit() {
+        let mut vm = vm_with_program(".ORIG x3000\nLOOP ADD R0, R0, #0\nBRnzp LOOP\n.END");
+        vm.set_instruction_limit(Some(100));
+        let mut debugger = Debugger::new(&mut vm);
+        assert_eq!(
+            Err(VMError::InstructionLimitExceeded(0x3001, 100)),
+            debugger.continue_execution()
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::hardware::{DecodedInstruction, Flags, Instruction};
+use crate::lc3_vm::VMError;
+
+/// Instruction decoded once and ready to be executed, together with the word it was decoded from.
+#[derive(Clone, Copy)]
+pub struct CachedInstruction {
+    pub word: u16,
+    pub instruction: Instruction,
+    pub decoded: DecodedInstruction,
+    /// Condition codes tested by a branch. Every 3 bit value is valid, so it's decoded for any instruction.
+    pub flags: Flags,
+}
+
+impl CachedInstruction {
+    #[inline]
+    pub fn decode(word: u16) -> Result<Self, VMError> {
+        let decoded =
+            DecodedInstruction::decode_instruction(word).map_err(VMError::InvalidInstruction)?;
+        Ok(Self {
+            word,
+            instruction: Instruction::from_u16(decoded.op_code)
+                .map_err(VMError::InvalidInstruction)?,
+            flags: Flags::from_u16(decoded.flags).map_err(VMError::InvalidInstruction)?,
+            decoded,
+        })
+    }
+}
+
+/// Number of consecutive addresses whose entries are allocated together.
+const PAGE_SIZE: usize = 256;
+
+type Page = [Option<CachedInstruction>; PAGE_SIZE];
+
+/// Decoded instructions by address, so the instructions of a loop are decoded only the first time they run.
+///
+/// Entries are invalidated when the vm writes their address. Memory is public and can also be changed without going
+/// through the vm, so an entry is only used if the word it was decoded from is still the one stored at its address.
+/// Entries are allocated in pages the first time an address of the page runs, so short runs don't pay for the whole
+/// address space.
+pub struct DecodeCache {
+    pages: Vec<Option<Box<Page>>>,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            pages: (0..(1 << 16) / PAGE_SIZE).map(|_| None).collect(),
+        }
+    }
+
+    /// Returns the instruction decoded from word at address, decoding it if it isn't cached yet.
+    #[inline]
+    pub fn get_or_decode(&mut self, address: u16, word: u16) -> Result<CachedInstruction, VMError> {
+        let (page, index) = (address as usize / PAGE_SIZE, address as usize % PAGE_SIZE);
+        if let Some(Some(page)) = self.pages.get(page)
+            && let Some(entry) = page[index]
+            && entry.word == word
+        {
+            return Ok(entry);
+        }
+        let entry = CachedInstruction::decode(word)?;
+        if let Some(slot) = self.pages.get_mut(page) {
+            slot.get_or_insert_with(|| Box::new([None; PAGE_SIZE]))[index] = Some(entry);
+        }
+        Ok(entry)
+    }
+
+    pub fn invalidate(&mut self, address: u16) {
+        if let Some(Some(page)) = self.pages.get_mut(address as usize / PAGE_SIZE) {
+            page[address as usize % PAGE_SIZE] = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.iter_mut().for_each(|page| *page = None);
+    }
+
+    /// Number of addresses with a decoded instruction.
+    pub fn len(&self) -> usize {
+        self.pages
+            .iter()
+            .flatten()
+            .map(|page| page.iter().filter(|entry| entry.is_some()).count())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_replaced_when_the_word_changes() {
+        let mut cache = DecodeCache::new();
+        assert!(cache.is_empty());
+        let add = cache.get_or_decode(0x3000, 0x1042).unwrap();
+        assert!(matches!(add.instruction, Instruction::OpADD));
+        assert_eq!(cache.len(), 1);
+        let halt = cache.get_or_decode(0x3000, 0xF025).unwrap();
+        assert!(matches!(halt.instruction, Instruction::OpTRAP));
+        assert_eq!(halt.decoded.trapvect8, 0x25);
+        cache.invalidate(0x3000);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::hardware::{DecodedInstruction, Instruction, Register, TrapCode};
+use crate::lc3_vm::VMError;
+
+/// Word of memory rendered as LC3 assembly.
+#[derive(PartialEq, Debug)]
+pub struct DisassembledWord {
+    pub address: u16,
+    pub word: u16,
+    pub text: String,
+}
+
+/// Extends sign for a number of imm_size bits and returns it as a signed value.
+fn signed(number: u16, imm_size: u32) -> i16 {
+    ((number << (16 - imm_size)) as i16) >> (16 - imm_size)
+}
+
+/// Absolute address of a PC relative operand. The PC is already incremented when the offset is added.
+fn target(address: u16, offset: u16, imm_size: u32) -> u16 {
+    address
+        .wrapping_add(1)
+        .wrapping_add(signed(offset, imm_size) as u16)
+}
+
+fn fill(word: u16) -> String {
+    format!(".FILL x{:04X}", word)
+}
+
+/// Renders a word stored at address as LC3 assembly. Offsets are resolved to absolute addresses and words that
+/// aren't a valid instruction are rendered as a .FILL directive.
+pub fn disassemble_instruction(address: u16, word: u16) -> String {
+    let Ok(decoded) = DecodedInstruction::decode_instruction(word) else {
+        return fill(word);
+    };
+    let Ok(instruction) = Instruction::from_u16(decoded.op_code) else {
+        return fill(word);
+    };
+    let second_operand = || {
+        if decoded.mode_alu == 1 {
+            format!("#{}", signed(decoded.alu_operand2, 5))
+        } else if decoded.alu_operand2 & 0b11000 != 0 {
+            String::new() // Bits 4 and 3 must be zero in register mode
+        } else {
+            format!("R{}", decoded.alu_operand2)
+        }
+    };
+    match instruction {
+        Instruction::OpBR => {
+            if decoded.flags == 0 {
+                // A branch that is never taken is most likely data.
+                return fill(word);
+            }
+            let mut mnemonic = String::from("BR");
+            if decoded.flags & 0b100 != 0 {
+                mnemonic.push('n');
+            }
+            if decoded.flags & 0b010 != 0 {
+                mnemonic.push('z');
+            }
+            if decoded.flags & 0b001 != 0 {
+                mnemonic.push('p');
+            }
+            format!("{} x{:04X}", mnemonic, target(address, decoded.imm9, 9))
+        }
+        Instruction::OpADD | Instruction::OpAND => {
+            let operand = second_operand();
+            if operand.is_empty() {
+                return fill(word);
+            }
+            let mnemonic = if matches!(instruction, Instruction::OpADD) {
+                "ADD"
+            } else {
+                "AND"
+            };
+            format!("{} {}, {}, {}", mnemonic, decoded.dst, decoded.src, operand)
+        }
+        Instruction::OpLD
+        | Instruction::OpST
+        | Instruction::OpLDI
+        | Instruction::OpSTI
+        | Instruction::OpLEA => {
+            let mnemonic = match instruction {
+                Instruction::OpLD => "LD",
+                Instruction::OpST => "ST",
+                Instruction::OpLDI => "LDI",
+                Instruction::OpSTI => "STI",
+                _ => "LEA",
+            };
+            format!(
+                "{} {}, x{:04X}",
+                mnemonic,
+                decoded.dst,
+                target(address, decoded.imm9, 9)
+            )
+        }
+        Instruction::OpJSR => {
+            if decoded.mode_jump == 1 {
+                format!("JSR x{:04X}", target(address, decoded.imm11, 11))
+            } else if word & 0x063F != 0 {
+                fill(word)
+            } else {
+                format!("JSRR {}", decoded.src)
+            }
+        }
+        Instruction::OpLDR | Instruction::OpSTR => {
+            let mnemonic = if matches!(instruction, Instruction::OpLDR) {
+                "LDR"
+            } else {
+                "STR"
+            };
+            format!(
+                "{} {}, {}, #{}",
+                mnemonic,
+                decoded.dst,
+                decoded.src,
+                signed(decoded.imm6, 6)
+            )
+        }
+        Instruction::OpRTI => {
+            if word != 0x8000 {
+                return fill(word);
+            }
+            String::from("RTI")
+        }
+        Instruction::OpRES => fill(word),
+        Instruction::OpNOT => {
+            if decoded.imm6 != 0x3F {
+                return fill(word);
+            }
+            format!("NOT {}, {}", decoded.dst, decoded.src)
+        }
+        Instruction::OpJMP => {
+            if word & 0x0E3F != 0 {
+                fill(word)
+            } else if decoded.src == Register::R7 {
+                String::from("RET")
+            } else {
+                format!("JMP {}", decoded.src)
+            }
+        }
+        Instruction::OpTRAP => {
+            if word & 0x0F00 != 0 {
+                return fill(word);
+            }
+            match TrapCode::from_u16(decoded.trapvect8) {
+                Ok(TrapCode::Getc) => String::from("GETC"),
+                Ok(TrapCode::Out) => String::from("OUT"),
+                Ok(TrapCode::Puts) => String::from("PUTS"),
+                Ok(TrapCode::In) => String::from("IN"),
+                Ok(TrapCode::Putsp) => String::from("PUTSP"),
+                Ok(TrapCode::Halt) => String::from("HALT"),
+                Err(_) => format!("TRAP x{:02X}", decoded.trapvect8),
+            }
+        }
+    }
+}
+
+/// Disassembles the words in memory from address start to address end, both included.
+/// It can be used with the memory of a running vm.
+pub fn disassemble_memory(memory: &[u16], start: u16, end: u16) -> Vec<DisassembledWord> {
+    (start as usize..=end as usize)
+        .filter(|address| *address < memory.len())
+        .map(|address| DisassembledWord {
+            address: address as u16,
+            word: memory[address],
+            text: disassemble_instruction(address as u16, memory[address]),
+        })
+        .collect()
+}
+
+/// Disassembles an object image. The first word of the image is the address where the rest of the image is loaded.
+pub fn disassemble_image(image: &[u8]) -> Result<Vec<DisassembledWord>, VMError> {
+    if !image.len().is_multiple_of(2) || image.len() < 2 {
+        return Err(VMError::FailedToLoadImage(String::from(
+            "Image size invalid",
+        )));
+    }
+    let origin = u16::from_be_bytes([image[0], image[1]]);
+    Ok(image[2..]
+        .chunks(2)
+        .enumerate()
+        .map(|(index, bytes)| {
+            let address = origin.wrapping_add(index as u16);
+            let word = u16::from_be_bytes([bytes[0], bytes[1]]);
+            DisassembledWord {
+                address,
+                word,
+                text: disassemble_instruction(address, word),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn disassembling_instructions_resolves_offsets() {
+        assert_eq!(disassemble_instruction(0x3000, 0x1042), "ADD R0, R1, R2");
+        assert_eq!(disassemble_instruction(0x3000, 0x107F), "ADD R0, R1, #-1");
+        assert_eq!(disassemble_instruction(0x3000, 0x5725), "AND R3, R4, #5");
+        assert_eq!(disassemble_instruction(0x3000, 0x9BBF), "NOT R5, R6");
+        assert_eq!(disassemble_instruction(0x3004, 0x0FFB), "BRnzp x3000");
+        assert_eq!(disassemble_instruction(0x3009, 0x4FF6), "JSR x3000");
+        assert_eq!(disassemble_instruction(0x300A, 0x40C0), "JSRR R3");
+        assert_eq!(disassemble_instruction(0x300B, 0x220D), "LD R1, x3019");
+        assert_eq!(disassemble_instruction(0x300D, 0x64FE), "LDR R2, R3, #-2");
+        assert_eq!(disassemble_instruction(0x3000, 0xC080), "JMP R2");
+        assert_eq!(disassemble_instruction(0x3000, 0xC1C0), "RET");
+        assert_eq!(disassemble_instruction(0x3000, 0xF025), "HALT");
+        assert_eq!(disassemble_instruction(0x3000, 0x8000), "RTI");
+        assert_eq!(disassemble_instruction(0x3000, 0xF030), "TRAP x30");
+    }
+
+    #[test]
+    fn non_code_words_are_rendered_as_fill() {
+        assert_eq!(disassemble_instruction(0x3000, 0x0068), ".FILL x0068");
+        assert_eq!(disassemble_instruction(0x3000, 0x8001), ".FILL x8001");
+        assert_eq!(disassemble_instruction(0x3000, 0xD123), ".FILL xD123");
+        assert_eq!(disassemble_instruction(0x3000, 0x1052), ".FILL x1052");
+        assert_eq!(disassemble_instruction(0x3000, 0x9BB0), ".FILL x9BB0");
+    }
+
+    #[test]
+    fn disassembling_assembled_image() {
+        let source = ".ORIG x3000\nLOOP ADD R1, R1, #-1\nBRp LOOP\nLEA R0, LOOP\nPUTS\nHALT\n.END";
+        let program = assemble(source).unwrap();
+        let disassembly = disassemble_image(&program.obj_image()).unwrap();
+        let texts: Vec<&str> = disassembly.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "ADD R1, R1, #-1",
+                "BRp x3000",
+                "LEA R0, x3000",
+                "PUTS",
+                "HALT"
+            ]
+        );
+        assert_eq!(disassembly[4].address, 0x3004);
+        assert_eq!(disassembly[4].word, 0xF025);
+    }
+
+    #[test]
+    fn disassembling_memory_range() {
+        let mut memory = [0u16; 1 << 16];
+        memory[0x3000] = 0xF025;
+        let disassembly = disassemble_memory(&memory, 0x3000, 0x3001);
+        assert_eq!(disassembly.len(), 2);
+        assert_eq!(disassembly[0].text, "HALT");
+        assert_eq!(disassembly[1].text, ".FILL x0000");
+        assert_eq!(disassemble_memory(&memory, 0xFFFF, 0xFFFF).len(), 1);
+    }
+}
@@ -0,0 +1,460 @@
+//! Stub of the GDB remote serial protocol, so GDB compatible front-ends can debug programs running on the vm.
+//!
+//! The ten registers (R0 to R7, PC and COND) are sent as 16 bit little endian values in that order.
+//! Memory addresses are word addresses and lengths are in bytes, two bytes per word in little endian format.
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::debugger::{Debugger, StopReason};
+use crate::hardware::Register;
+use crate::lc3_vm::{LC3VirtualMachine, VMError};
+
+/// Number of instructions executed between checks for an interrupt request from the client.
+const INTERRUPT_CHECK_PERIOD: usize = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+fn io_error(error: std::io::Error) -> VMError {
+    VMError::IOError(format!("{:?}", error))
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn encode_words(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    encode_bytes(&bytes)
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_words(hex: &str) -> Option<Vec<u16>> {
+    let bytes = decode_bytes(hex)?;
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect(),
+    )
+}
+
+/// Parses the "address,length" arguments of memory and breakpoint packets.
+fn parse_address_and_length(arguments: &str) -> Option<(u16, usize)> {
+    let (address, length) = arguments.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+/// Connection with a GDB client debugging a vm.
+pub struct GdbStub<'a> {
+    debugger: Debugger<'a>,
+    stream: TcpStream,
+    /// Bytes received while the program ran, other than interrupt requests, which the packet reader reads first.
+    received: VecDeque<u8>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(vm: &'a mut LC3VirtualMachine, stream: TcpStream) -> Self {
+        Self {
+            debugger: Debugger::new(vm),
+            stream,
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Serves packets until the client detaches, kills the program or closes the connection.
+    pub fn serve(&mut self) -> Result<(), VMError> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle_packet(&packet)? {
+                Some(response) => self.send_packet(&response)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next packet, acknowledging it. Returns None if the connection was closed.
+    fn read_packet(&mut self) -> Result<Option<String>, VMError> {
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(None);
+            };
+            if byte != b'$' {
+                continue; // Acknowledgments and interrupts outside of an execution are ignored.
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut received_checksum = [0; 2];
+            for byte in &mut received_checksum {
+                let Some(checksum_byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+                *byte = checksum_byte;
+            }
+            let data = String::from_utf8_lossy(&data).to_string();
+            let valid = std::str::from_utf8(&received_checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(checksum(&data));
+            if valid {
+                self.stream.write_all(b"+").map_err(io_error)?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-").map_err(io_error)?;
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, VMError> {
+        if let Some(byte) = self.received.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut buffer = [0; 1];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buffer[0])),
+            Err(error) if error.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(error) => Err(io_error(error)),
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), VMError> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream.write_all(packet.as_bytes()).map_err(io_error)?;
+        self.stream.flush().map_err(io_error)
+    }
+
+    /// Checks without blocking if the client sent an interrupt (Ctrl-C) request. Any other byte received is kept
+    /// for the packet reader.
+    fn interrupt_requested(&mut self) -> Result<bool, VMError> {
+        self.stream.set_nonblocking(true).map_err(io_error)?;
+        let mut buffer = [0; 64];
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false).map_err(io_error)?;
+        let length = match result {
+            Ok(length) => length,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => 0,
+            Err(error) => return Err(io_error(error)),
+        };
+        let mut requested = false;
+        for byte in &buffer[..length] {
+            match byte {
+                0x03 => requested = true,
+                _ => self.received.push_back(*byte),
+            }
+        }
+        Ok(requested)
+    }
+
+    /// Runs the vm until it stops and returns the stop reply for the client.
+    fn resume(&mut self, single_step: bool) -> Result<String, VMError> {
+        let mut executed = 0;
+        loop {
+            match self.debugger.step(1) {
+                Ok(StopReason::Halted) => return Ok(String::from("W00")),
+                Ok(StopReason::Breakpoint(_) | StopReason::WaitingForInput) => {
+                    return Ok(format!("S{:02x}", SIGTRAP));
+                }
+                Ok(StopReason::Stepped) if single_step => return Ok(format!("S{:02x}", SIGTRAP)),
+                Ok(StopReason::Stepped) => {}
+                Err(error) => {
+                    // The client prints the console output packet, so the user learns why the program stopped.
+                    let message = format!("Error: {}\n", error);
+                    self.send_packet(&format!("O{}", encode_bytes(message.as_bytes())))?;
+                    return Ok(format!("S{:02x}", SIGILL));
+                }
+            }
+            executed += 1;
+            if executed % INTERRUPT_CHECK_PERIOD == 0 && self.interrupt_requested()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    /// Handles a packet and returns the response, or None if the session has to finish.
+    fn handle_packet(&mut self, packet: &str) -> Result<Option<String>, VMError> {
+        let Some(command) = packet.chars().next() else {
+            return Ok(Some(String::new()));
+        };
+        let arguments = &packet[command.len_utf8()..];
+        let response = match command {
+            '?' => format!("S{:02x}", SIGTRAP),
+            'g' => encode_words(&self.debugger.vm().registers),
+            'G' => match decode_words(arguments) {
+                Some(values) if values.len() == 10 => {
+                    self.debugger.vm().registers.copy_from_slice(&values);
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            'p' => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < 10 => {
+                    encode_words(&[self.debugger.vm().registers[register]])
+                }
+                _ => String::from("E01"),
+            },
+            'P' => {
+                let parsed = arguments.split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok()?;
+                    let value = decode_words(value)?;
+                    (register < 10 && value.len() == 1).then_some((register, value[0]))
+                });
+                match parsed {
+                    Some((register, value)) => {
+                        self.debugger.vm().registers[register] = value;
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            'm' => match parse_address_and_length(arguments) {
+                Some((address, length)) => {
+                    let memory = &self.debugger.vm().memory;
+                    let words: Vec<u16> = (0..length.div_ceil(2))
+                        .map(|offset| memory[address.wrapping_add(offset as u16) as usize])
+                        .collect();
+                    let mut hex = encode_words(&words);
+                    hex.truncate(length * 2);
+                    hex
+                }
+                None => String::from("E01"),
+            },
+            'M' => {
+                let parsed = arguments.split_once(':').and_then(|(location, data)| {
+                    let (address, length) = parse_address_and_length(location)?;
+                    let words = decode_words(data)?;
+                    (words.len() * 2 == length).then_some((address, words))
+                });
+                match parsed {
+                    Some((address, words)) => {
+                        let vm = self.debugger.vm();
+                        for (offset, word) in words.iter().enumerate() {
+                            vm.write_word(address.wrapping_add(offset as u16), *word)?;
+                        }
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            'Z' | 'z' => {
+                let breakpoint = arguments
+                    .strip_prefix("0,")
+                    .and_then(parse_address_and_length);
+                match breakpoint {
+                    Some((address, _)) if command == 'Z' => {
+                        self.debugger.add_breakpoint(address);
+                        String::from("OK")
+                    }
+                    Some((address, _)) => {
+                        self.debugger.remove_breakpoint(address);
+                        String::from("OK")
+                    }
+                    None => String::new(), // Only software breakpoints are supported
+                }
+            }
+            'c' | 's' => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    self.debugger.vm().registers[Register::PC] = address;
+                }
+                self.resume(command == 's')?
+            }
+            'H' => String::from("OK"),
+            'D' => {
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            'k' => return Ok(None),
+            'q' if packet.starts_with("qSupported") => String::from("PacketSize=4000"),
+            'q' if packet == "qAttached" => String::from("1"),
+            'q' if packet == "qC" => String::from("QC1"),
+            'q' if packet == "qfThreadInfo" => String::from("m1"),
+            'q' if packet == "qsThreadInfo" => String::from("l"),
+            _ => String::new(), // Empty response for unsupported packets
+        };
+        Ok(Some(response))
+    }
+}
+
+/// Waits for a GDB client on the listener and serves it until the session finishes.
+pub fn serve_gdb(vm: &mut LC3VirtualMachine, listener: &TcpListener) -> Result<(), VMError> {
+    let (stream, _) = listener.accept().map_err(io_error)?;
+    stream.set_nodelay(true).map_err(io_error)?;
+    GdbStub::new(vm, stream).serve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::hardware::TrapMode;
+    use crate::lc3_vm::read_image_file;
+    use std::thread;
+
+    /// Sends a packet and returns the response, checking the acknowledgment.
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        send(stream, data);
+        read_reply(stream)
+    }
+
+    /// Sends a packet and checks the acknowledgment.
+    fn send(stream: &mut TcpStream, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        stream.write_all(packet.as_bytes()).unwrap();
+        read_ack(stream);
+    }
+
+    fn read_ack(stream: &mut TcpStream) {
+        let mut ack = [0; 1];
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+    }
+
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            response.push(byte[0]);
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        String::from_utf8(response[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn gdb_client_reads_writes_and_runs_the_vm() {
+        let program =
+            assemble(".ORIG x3000\nADD R0, R0, #1\nADD R0, R0, #2\nADD R0, R0, #3\nHALT\n.END")
+                .unwrap();
+        let mut vm = LC3VirtualMachine::new();
+        vm.set_trap_mode(TrapMode::Native);
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        vm.set_console(Box::new(console));
+        read_image_file(&mut vm, program.obj_image()).unwrap();
+        vm.set_pc_with_origin();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut responses = Vec::new();
+            for packet in [
+                "qSupported:multiprocess+",
+                "?",
+                "p8",
+                "m3000,4",
+                "Z0,3002,2",
+                "c",
+                "g",
+                "s",
+                "P1=3412",
+                "M4000,2:0700",
+                "m4000,2",
+                "z0,3002,2",
+                "c",
+                "Mfe06,2:4100",
+                "D",
+            ] {
+                responses.push(request(&mut stream, packet));
+            }
+            responses
+        });
+        assert_eq!(Ok(()), serve_gdb(&mut vm, &listener));
+        let responses = client.join().unwrap();
+        assert_eq!(responses[0], "PacketSize=4000");
+        assert_eq!(responses[1], "S05");
+        assert_eq!(responses[2], "0030");
+        assert_eq!(responses[3], "21102210");
+        assert_eq!(responses[4], "OK");
+        assert_eq!(responses[5], "S05");
+        // R0 = 3, R1 to R5 = 0, R6 = x3000, R7 = 0, PC = x3002 and COND = Pos.
+        assert_eq!(
+            responses[6],
+            format!("0300{}0030000002300100", "0000".repeat(5))
+        );
+        assert_eq!(responses[7], "S05");
+        assert_eq!(responses[8], "OK");
+        assert_eq!(responses[9], "OK");
+        assert_eq!(responses[10], "0700");
+        assert_eq!(responses[11], "OK");
+        assert_eq!(responses[12], "W00");
+        assert_eq!(responses[13], "OK");
+        assert_eq!(responses[14], "OK");
+        // Writing the display data register prints the character.
+        assert_eq!(*output.lock().unwrap(), b"A");
+        assert_eq!(vm.registers[Register::R0], 6);
+        assert_eq!(vm.registers[Register::R1], 0x1234);
+        assert_eq!(vm.memory[0x4000], 7);
+    }
+
+    #[test]
+    fn gdb_client_interrupts_the_program_and_reads_why_it_failed() {
+        let program =
+            assemble(".ORIG x3000\nLOOP AND R0, R0, #0\nBRz LOOP\n.FILL xD000\n.END").unwrap();
+        let mut vm = LC3VirtualMachine::new();
+        vm.set_trap_mode(TrapMode::Native);
+        read_image_file(&mut vm, program.obj_image()).unwrap();
+        vm.set_pc_with_origin();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut responses = Vec::new();
+            send(&mut stream, "c");
+            // A packet sent while the program runs is answered once the interrupt stops it.
+            stream.write_all(b"$?#3f\x03").unwrap();
+            responses.push(read_reply(&mut stream));
+            read_ack(&mut stream);
+            responses.push(read_reply(&mut stream));
+            responses.push(request(&mut stream, "P8=0230"));
+            send(&mut stream, "c");
+            responses.push(read_reply(&mut stream));
+            responses.push(read_reply(&mut stream));
+            responses.push(request(&mut stream, "D"));
+            responses
+        });
+        assert_eq!(Ok(()), serve_gdb(&mut vm, &listener));
+        let responses = client.join().unwrap();
+        assert_eq!(responses[0], "S02");
+        assert_eq!(responses[1], "S05");
+        assert_eq!(responses[2], "OK");
+        let message = decode_bytes(responses[3].strip_prefix('O').unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "Error: Invalid Instruction: Invalid OP Code: 13\n"
+        );
+        assert_eq!(responses[4], "S04");
+        assert_eq!(responses[5], "OK");
+    }
+}
@@ -0,0 +1,657 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::assembler::parse_number;
+use crate::call_stack::CallKind;
+use crate::console::ScriptedConsole;
+use crate::coverage::Coverage;
+use crate::hardware::{ExecutionEngine, Privilege, PsrMask, Register, TrapMode};
+use crate::lc3_vm::{LC3VirtualMachine, VMError};
+
+/// Instructions a test case may execute when neither the case, the suite nor the grading options set a limit.
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 10_000_000;
+
+/// Configuration of the vms that run the cases, like the one given in the command line.
+#[derive(Clone, Debug)]
+pub struct GradingOptions {
+    /// Instruction limit of the cases when neither the case nor the suite set one.
+    pub instruction_limit: Option<u64>,
+    /// Time every case can run for.
+    pub timeout: Option<Duration>,
+    /// How the trap routines run, from memory by default like in the builder.
+    pub trap_mode: TrapMode,
+    /// OS image loaded instead of the default one when the trap routines run from memory.
+    pub os_image: Option<Vec<u8>>,
+    /// Privilege mode the programs start in, user mode by default like in the builder.
+    pub privilege: Privilege,
+    /// Engine that executes the programs.
+    pub engine: ExecutionEngine,
+}
+
+impl Default for GradingOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GradingOptions {
+    pub fn new() -> Self {
+        Self {
+            instruction_limit: None,
+            timeout: None,
+            trap_mode: TrapMode::default(),
+            os_image: None,
+            privilege: Privilege::User,
+            engine: ExecutionEngine::Interpreter,
+        }
+    }
+}
+
+/// Writer that keeps everything written to it in a buffer shared with the grader.
+struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|error| io::Error::other(error.to_string()))?
+            .extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Word of a test suite, written as a number or as an LC3 literal such as "x3000" or "#-1".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawWord {
+    Number(i64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+struct RawTestCase {
+    name: String,
+    #[serde(default)]
+    input: String,
+    output: Option<String>,
+    #[serde(default)]
+    registers: BTreeMap<String, RawWord>,
+    #[serde(default)]
+    memory: BTreeMap<String, RawWord>,
+    instruction_limit: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawTestSuite {
+    instruction_limit: Option<u64>,
+    #[serde(rename = "case", alias = "cases", default)]
+    cases: Vec<RawTestCase>,
+}
+
+/// Case of a test suite: the input given to the program and what is expected once it halts.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TestCase {
+    pub name: String,
+    pub input: Vec<u8>,
+    /// Whole console output expected, or None to leave the output unchecked.
+    pub output: Option<String>,
+    pub registers: Vec<(Register, u16)>,
+    pub memory: Vec<(u16, u16)>,
+    pub instruction_limit: Option<u64>,
+}
+
+/// Test cases to grade an image with.
+///
+/// Suites are written in TOML, with one `[[case]]` table per case, or in JSON, with the cases in a `cases` array.
+/// Registers and memory addresses are keys of the `registers` and `memory` tables of each case, and their expected
+/// values can be numbers or LC3 literals.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TestSuite {
+    pub instruction_limit: Option<u64>,
+    pub cases: Vec<TestCase>,
+}
+
+fn invalid_suite(message: String) -> VMError {
+    VMError::InvalidTestSuite(message)
+}
+
+fn parse_word(value: &RawWord, key: &str) -> Result<u16, VMError> {
+    let number = match value {
+        RawWord::Number(number) => *number,
+        RawWord::Text(text) => parse_number(text.trim())
+            .ok_or_else(|| invalid_suite(format!("Invalid value {:?} for {}", text, key)))?
+            as i64,
+    };
+    if !(i16::MIN as i64..=u16::MAX as i64).contains(&number) {
+        return Err(invalid_suite(format!(
+            "Value {} for {} doesn't fit in a word",
+            number, key
+        )));
+    }
+    Ok(number as u16)
+}
+
+fn parse_register(name: &str) -> Result<Register, VMError> {
+    let index = match name.to_ascii_uppercase().as_str() {
+        "PC" => 8,
+        "COND" => 9,
+        register => register
+            .strip_prefix('R')
+            .and_then(|number| number.parse::<u16>().ok())
+            .filter(|number| *number < 8)
+            .ok_or_else(|| invalid_suite(format!("Invalid register {}", name)))?,
+    };
+    Register::from_u16(index).map_err(|_| invalid_suite(format!("Invalid register {}", name)))
+}
+
+fn parse_address(address: &str) -> Result<u16, VMError> {
+    parse_number(address)
+        .filter(|number| (0..=u16::MAX as i32).contains(number))
+        .map(|number| number as u16)
+        .ok_or_else(|| invalid_suite(format!("Invalid address {}", address)))
+}
+
+impl TestCase {
+    fn from_raw(raw: RawTestCase) -> Result<Self, VMError> {
+        let registers = raw
+            .registers
+            .iter()
+            .map(|(name, value)| Ok((parse_register(name)?, parse_word(value, name)?)))
+            .collect::<Result<Vec<_>, VMError>>()?;
+        let memory = raw
+            .memory
+            .iter()
+            .map(|(address, value)| Ok((parse_address(address)?, parse_word(value, address)?)))
+            .collect::<Result<Vec<_>, VMError>>()?;
+        Ok(Self {
+            name: raw.name,
+            input: raw.input.into_bytes(),
+            output: raw.output,
+            registers,
+            memory,
+            instruction_limit: raw.instruction_limit,
+        })
+    }
+}
+
+impl TestSuite {
+    fn from_raw(raw: RawTestSuite) -> Result<Self, VMError> {
+        Ok(Self {
+            instruction_limit: raw.instruction_limit,
+            cases: raw
+                .cases
+                .into_iter()
+                .map(TestCase::from_raw)
+                .collect::<Result<Vec<_>, VMError>>()?,
+        })
+    }
+
+    pub fn parse_toml(text: &str) -> Result<Self, VMError> {
+        Self::from_raw(toml::from_str(text).map_err(|error| invalid_suite(error.to_string()))?)
+    }
+
+    pub fn parse_json(text: &str) -> Result<Self, VMError> {
+        Self::from_raw(
+            serde_json::from_str(text).map_err(|error| invalid_suite(error.to_string()))?,
+        )
+    }
+
+    /// Reads the suite in the file at path, as JSON if it has .json extension and as TOML otherwise.
+    pub fn load(path: &str) -> Result<Self, VMError> {
+        let text =
+            fs::read_to_string(path).map_err(|error| invalid_suite(format!("{:?}", error)))?;
+        if Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+        {
+            Self::parse_json(&text)
+        } else {
+            Self::parse_toml(&text)
+        }
+    }
+
+    /// Options of the suite, which are the given ones with the instruction limit of the suite if it sets one.
+    fn options(&self, options: &GradingOptions) -> GradingOptions {
+        GradingOptions {
+            instruction_limit: self.instruction_limit.or(options.instruction_limit),
+            ..options.clone()
+        }
+    }
+
+    /// Runs every case of the suite on a fresh vm loaded with image and configured with options.
+    pub fn run(&self, image: &[u8], options: &GradingOptions) -> Vec<CaseResult> {
+        let options = self.options(options);
+        self.cases
+            .iter()
+            .map(|case| run_test_case(image, case, &options))
+            .collect()
+    }
+
+    /// Runs every case like [`TestSuite::run`], adding up in coverage the instructions executed by all of them.
+    pub fn run_with_coverage(
+        &self,
+        image: &[u8],
+        options: &GradingOptions,
+        coverage: &mut Coverage,
+    ) -> Vec<CaseResult> {
+        let options = self.options(options);
+        self.cases
+            .iter()
+            .map(|case| run_case(image, case, &options, Some(&mut *coverage)))
+            .collect()
+    }
+}
+
+/// Outcome of a test case, with a description of every difference from the expected results.
+#[derive(PartialEq, Debug)]
+pub struct CaseResult {
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for CaseResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = if self.passed() { "PASS" } else { "FAIL" };
+        write!(f, "{} {}", status, self.name)?;
+        for failure in &self.failures {
+            write!(f, "\n    {}", failure.replace('\n', "\n      "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes the lines where the output differs from the expected one.
+fn output_diff(expected: &str, actual: &str) -> Vec<String> {
+    let expected_lines: Vec<&str> = expected.split('\n').collect();
+    let actual_lines: Vec<&str> = actual.split('\n').collect();
+    (0..expected_lines.len().max(actual_lines.len()))
+        .filter(|index| expected_lines.get(*index) != actual_lines.get(*index))
+        .map(
+            |index| match (expected_lines.get(index), actual_lines.get(index)) {
+                (Some(expected), Some(actual)) => format!(
+                    "output line {}: expected {:?}, got {:?}",
+                    index + 1,
+                    expected,
+                    actual
+                ),
+                (Some(expected), None) => {
+                    format!(
+                        "output line {}: expected {:?}, got nothing",
+                        index + 1,
+                        expected
+                    )
+                }
+                (None, actual) => format!(
+                    "output line {}: unexpected {:?}",
+                    index + 1,
+                    actual.unwrap_or(&"")
+                ),
+            },
+        )
+        .collect()
+}
+
+/// Registers of the program once it halted. HALT stops the vm inside its routine when the trap routines run from
+/// memory, so the PC, the condition codes and R6 are the ones RTI would restore from the stack, and R7 holds the
+/// return address as TRAP leaves it.
+fn program_registers(vm: &LC3VirtualMachine) -> [u16; 10] {
+    let mut registers = vm.registers;
+    let in_trap = vm
+        .call_stack()
+        .frames()
+        .back()
+        .is_some_and(|frame| matches!(frame.kind, CallKind::Trap(_)));
+    if in_trap {
+        let stack = vm.registers[Register::R6];
+        let pc = vm.memory[stack as usize];
+        let psr = vm.memory[stack.wrapping_add(1) as usize];
+        registers[Register::PC] = pc;
+        registers[Register::R7] = pc;
+        registers[Register::COND] = psr & PsrMask::Cond as u16;
+        registers[Register::R6] = if psr & PsrMask::Privilege as u16 != 0 {
+            vm.saved_usp
+        } else {
+            stack.wrapping_add(2)
+        };
+    }
+    registers
+}
+
+/// Runs a test case on a fresh vm loaded with image and configured with options, capturing its output.
+///
+/// Once the program consumed the whole input, reading or polling the keyboard stops it, so a program waiting for a
+/// key fails at once instead of running until the instruction limit. The registers and memory are only checked if
+/// the program halts within the instruction limit of the case, or of the options when the case has none.
+pub fn run_test_case(image: &[u8], case: &TestCase, options: &GradingOptions) -> CaseResult {
+    run_case(image, case, options, None)
+}
+
+fn run_case(
+    image: &[u8],
+    case: &TestCase,
+    options: &GradingOptions,
+    mut coverage: Option<&mut Coverage>,
+) -> CaseResult {
+    let mut result = CaseResult {
+        name: case.name.clone(),
+        failures: Vec::new(),
+    };
+    let limit = case
+        .instruction_limit
+        .or(options.instruction_limit)
+        .unwrap_or(DEFAULT_INSTRUCTION_LIMIT);
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let console = ScriptedConsole::new(&case.input, CapturedOutput(Arc::clone(&output)));
+    let mut builder = LC3VirtualMachine::builder()
+        .trap_mode(options.trap_mode)
+        .privilege(options.privilege)
+        .engine(options.engine)
+        .console(console)
+        .instruction_limit(limit)
+        .image(image.to_vec());
+    if let Some(timeout) = options.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(os_image) = &options.os_image {
+        builder = builder.os_image(os_image.clone());
+    }
+    let mut vm = match builder.build() {
+        Ok(vm) => vm,
+        Err(error) => {
+            result.failures.push(error.to_string());
+            return result;
+        }
+    };
+
+    vm.set_coverage(coverage.as_mut().map(|coverage| std::mem::take(*coverage)));
+    let run_result = vm.run();
+    if let (Some(coverage), Some(recorded)) = (coverage, vm.take_coverage()) {
+        *coverage = recorded;
+    }
+    let halted = match run_result {
+        Ok(()) => true,
+        Err(VMError::EndOfInput) => {
+            result.failures.push(format!(
+                "the program needs more input than it was given\n{}",
+                vm.backtrace()
+            ));
+            false
+        }
+        Err(VMError::InstructionLimitExceeded(pc, _)) => {
+            result.failures.push(format!(
+                "the program didn't halt within {} instructions, it was running at x{:04X}\n{}",
+                limit,
+                pc,
+                vm.backtrace()
+            ));
+            false
+        }
+        Err(VMError::Timeout(pc, _)) => {
+            result.failures.push(format!(
+                "the program didn't halt within {:?}, it was running at x{:04X}\n{}",
+                options.timeout.unwrap_or_default(),
+                pc,
+                vm.backtrace()
+            ));
+            false
+        }
+        Err(error) => {
+            result
+                .failures
+                .push(format!("the program failed: {}\n{}", error, vm.backtrace()));
+            false
+        }
+    };
+
+    if let Some(expected) = &case.output {
+        let actual = String::from_utf8_lossy(&output.lock().unwrap()).into_owned();
+        result.failures.extend(output_diff(expected, &actual));
+    }
+    if halted {
+        let registers = program_registers(&vm);
+        for (register, expected) in &case.registers {
+            let actual = registers[*register];
+            if actual != *expected {
+                result.failures.push(format!(
+                    "{}: expected x{:04X}, got x{:04X}",
+                    register, expected, actual
+                ));
+            }
+        }
+        for (address, expected) in &case.memory {
+            let actual = vm.memory[*address as usize];
+            if actual != *expected {
+                result.failures.push(format!(
+                    "memory x{:04X}: expected x{:04X}, got x{:04X}",
+                    address, expected, actual
+                ));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    /// Reads a digit, prints the next one and stores it in R2 and in RESULT.
+    fn next_digit_program() -> Vec<u8> {
+        assemble(
+            "
+            .ORIG x3000
+            GETC
+            ADD R0, R0, #1
+            OUT
+            ADD R2, R0, #0
+            ST R2, RESULT
+            HALT
+    RESULT  .BLKW 1
+            .END",
+        )
+        .unwrap()
+        .obj_image()
+    }
+
+    #[test]
+    fn toml_and_json_suites_are_equivalent() {
+        let toml = TestSuite::parse_toml(
+            r#"
+            instruction_limit = 100
+
+            [[case]]
+            name = "next digit"
+            input = "3"
+            output = "4"
+            registers = { R2 = "x0034" }
+            memory = { x3006 = 52 }
+            "#,
+        )
+        .unwrap();
+        let json = TestSuite::parse_json(
+            r##"{
+                "instruction_limit": 100,
+                "cases": [{
+                    "name": "next digit",
+                    "input": "3",
+                    "output": "4",
+                    "registers": { "r2": 52 },
+                    "memory": { "0x3006": "#52" }
+                }]
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(toml.cases[0].registers, vec![(Register::R2, 0x34)]);
+        assert_eq!(toml.cases[0].memory, vec![(0x3006, 0x34)]);
+        assert!(matches!(
+            TestSuite::parse_toml("[[case]]\nname = \"bad\"\nregisters = { R8 = 1 }"),
+            Err(VMError::InvalidTestSuite(_))
+        ));
+    }
+
+    #[test]
+    fn cases_report_every_difference() {
+        let suite = TestSuite::parse_toml(
+            r#"
+            [[case]]
+            name = "passes"
+            input = "3"
+            output = "4"
+            registers = { R2 = "x34" }
+            memory = { x3006 = "x34" }
+
+            [[case]]
+            name = "fails"
+            input = "5"
+            output = "7"
+            registers = { R2 = "x37" }
+            "#,
+        )
+        .unwrap();
+        let results = suite.run(&next_digit_program(), &GradingOptions::new());
+        assert!(results[0].passed());
+        assert_eq!(
+            results[1].failures,
+            vec![
+                "output line 1: expected \"7\", got \"6\"",
+                "R2: expected x0037, got x0036"
+            ]
+        );
+        assert_eq!(
+            results[1].to_string(),
+            "FAIL fails\n    output line 1: expected \"7\", got \"6\"\n    R2: expected x0037, got x0036"
+        );
+    }
+
+    #[test]
+    fn programs_that_dont_halt_fail() {
+        let suite = TestSuite::parse_toml(
+            r#"
+            [[case]]
+            name = "no input"
+            registers = { R2 = 0 }
+
+            [[case]]
+            name = "too slow"
+            input = "1"
+            instruction_limit = 2
+            "#,
+        )
+        .unwrap();
+        let results = suite.run(&next_digit_program(), &GradingOptions::new());
+        assert_eq!(
+            results[0].failures,
+            vec![
+                "the program needs more input than it was given\n#0 x0247\n\
+                 #1 x0201: JSR to x0247, returns to x0202\n\
+                 #2 x3000: TRAP x20 to x0200, returns to x3001"
+            ]
+        );
+        assert_eq!(
+            results[1].to_string(),
+            "FAIL too slow\n    the program didn't halt within 2 instructions, it was running at x0200\n      \
+             #0 x0200\n      #1 x3000: TRAP x20 to x0200, returns to x3001"
+        );
+    }
+
+    #[test]
+    fn programs_polling_the_keyboard_stop_when_the_input_runs_out() {
+        let program = assemble(
+            "
+            .ORIG x3000
+    POLL    LDI R1, KBSR
+            BRzp POLL
+            LDI R0, KBDR
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END",
+        )
+        .unwrap();
+        let suite = TestSuite::parse_toml(
+            r#"
+            [[case]]
+            name = "key"
+            input = "k"
+            registers = { R0 = "x6B" }
+
+            [[case]]
+            name = "no key"
+            "#,
+        )
+        .unwrap();
+        let mut options = GradingOptions::new();
+        options.privilege = Privilege::Supervisor;
+        let results = suite.run(&program.obj_image(), &options);
+        assert!(results[0].passed());
+        assert_eq!(
+            results[1].failures,
+            vec!["the program needs more input than it was given\n#0 x3000"]
+        );
+    }
+
+    #[test]
+    fn options_apply_to_cases_without_their_own() {
+        let suite = TestSuite::parse_toml(
+            r#"
+            [[case]]
+            name = "default limit"
+            input = "1"
+            output = "2"
+
+            [[case]]
+            name = "own limit"
+            input = "1"
+            output = "2"
+            instruction_limit = 100
+            "#,
+        )
+        .unwrap();
+        let mut options = GradingOptions::new();
+        options.instruction_limit = Some(3);
+        let results = suite.run(&next_digit_program(), &options);
+        assert!(
+            results[0].failures[0].starts_with("the program didn't halt within 3 instructions")
+        );
+        // The OS routines take more instructions than native ones, but still fewer than the limit of the case.
+        assert!(results[1].passed());
+    }
+
+    #[test]
+    fn registers_are_checked_as_halt_leaves_them_in_both_trap_modes() {
+        let suite = TestSuite::parse_toml(
+            r#"
+            [[case]]
+            name = "next digit"
+            input = "3"
+            registers = { R0 = "x34", R1 = 0, R6 = 0, R7 = "x3006", PC = "x3006", COND = 1 }
+            "#,
+        )
+        .unwrap();
+        for trap_mode in [TrapMode::Native, TrapMode::MemoryResident] {
+            let mut options = GradingOptions::new();
+            options.trap_mode = trap_mode;
+            let results = suite.run(&next_digit_program(), &options);
+            assert!(results[0].passed(), "{:?}: {}", trap_mode, results[0]);
+        }
+    }
+}
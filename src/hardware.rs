@@ -22,6 +22,8 @@ impl fmt::Display for HardwareError {
         f.write_str(description)
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Register {
     R0,
     R1,
@@ -55,6 +57,24 @@ impl Register {
     }
 }
 
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Register::R0 => "R0",
+            Register::R1 => "R1",
+            Register::R2 => "R2",
+            Register::R3 => "R3",
+            Register::R4 => "R4",
+            Register::R5 => "R5",
+            Register::R6 => "R6",
+            Register::R7 => "R7",
+            Register::PC => "PC",
+            Register::COND => "COND",
+        };
+        f.write_str(name)
+    }
+}
+
 impl<T> Index<Register> for [T] {
     type Output = T;
 
@@ -91,6 +111,7 @@ impl<T> IndexMut<Register> for [T] {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Flags {
     Pos,
     Zro,
@@ -120,6 +141,7 @@ impl Flags {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Instruction {
     OpBR,   /* branch */
     OpADD,  /* add  */
@@ -129,10 +151,12 @@ pub enum Instruction {
     OpAND,  /* bitwise and */
     OpLDR,  /* load register */
     OpSTR,  /* store register */
+    OpRTI,  /* return from interrupt */
     OpNOT,  /* bitwise not */
     OpLDI,  /* load indirect */
     OpSTI,  /* store indirect */
     OpJMP,  /* jump */
+    OpRES,  /* reserved (illegal opcode) */
     OpLEA,  /* load effective address */
     OpTRAP, /* execute trap */
 }
@@ -148,10 +172,12 @@ impl Instruction {
             5 => Ok(Self::OpAND),   /* bitwise and */
             6 => Ok(Self::OpLDR),   /* load register */
             7 => Ok(Self::OpSTR),   /* store register */
+            8 => Ok(Self::OpRTI),   /* return from interrupt */
             9 => Ok(Self::OpNOT),   /* bitwise not */
             10 => Ok(Self::OpLDI),  /* load indirect */
             11 => Ok(Self::OpSTI),  /* store indirect */
             12 => Ok(Self::OpJMP),  /* jump */
+            13 => Ok(Self::OpRES),  /* reserved (illegal opcode) */
             14 => Ok(Self::OpLEA),  /* load effective address */
             15 => Ok(Self::OpTRAP), /* execute trap */
             _ => Err(HardwareError::InvalidInstruction(value)),
@@ -159,6 +185,7 @@ impl Instruction {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct DecodedInstruction {
     pub op_code: u16,
     pub dst: Register,
@@ -218,7 +245,70 @@ impl TrapCode {
     }
 }
 
+/// How the TRAP instruction runs the service routines. Both [`crate::LC3VirtualMachine::new`] and the builder use
+/// the default, memory resident routines from the default OS image.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum TrapMode {
+    Native, /* routines implemented by the vm */
+    #[default]
+    MemoryResident, /* jump to the address in the trap vector table */
+}
+
+/// How the vm executes the program.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExecutionEngine {
+    Interpreter, /* fetch, decode and execute one instruction at a time */
+    BasicBlock,  /* run straight-line code compiled into closures */
+}
+
 pub enum MemoryMappedRegisters {
     MrKBSR = 0xFE00, /* keyboard status */
     MrKBDR = 0xFE02, /* keyboard data */
+    MrDSR = 0xFE04,  /* display status */
+    MrDDR = 0xFE06,  /* display data */
+    MrTSR = 0xFE08,  /* timer status */
+    MrTIR = 0xFE0A,  /* timer interval */
+    MrTCR = 0xFE0C,  /* timer count */
+    MrPSR = 0xFFFC,  /* processor status register */
+    MrMCR = 0xFFFE,  /* machine control register */
+}
+
+/// Bits of the device status registers.
+pub enum StatusBits {
+    Ready = 0x8000,           /* bit 15 */
+    InterruptEnable = 0x4000, /* bit 14 */
+}
+
+/// Bit of the machine control register that keeps the clock running.
+pub const MCR_CLOCK_ENABLE: u16 = 0x8000;
+
+/// Privilege mode, stored in bit 15 of the PSR.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Privilege {
+    Supervisor = 0,
+    User = 1,
+}
+
+/// Masks of the fields in the processor status register.
+pub enum PsrMask {
+    Privilege = 0x8000, /* bit 15 */
+    Priority = 0x0700,  /* bits 10 to 8 */
+    Cond = 0x0007,      /* bits 2 to 0 */
+}
+
+/// Exceptions have their entries at the start of the interrupt vector table.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExceptionVector {
+    PrivilegeModeViolation = 0x00,
+    IllegalOpcode = 0x01,
+    AccessControlViolation = 0x02,
+}
+
+/// Base address of the interrupt vector table, which spans from x0100 to x01FF.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+/// Returns true for the addresses only accessible in supervisor mode: the system space (x0000 to x2FFF) and the
+/// device register addresses (xFE00 to xFFFF).
+pub fn is_privileged_address(address: u16) -> bool {
+    !(0x3000..0xFE00).contains(&address)
 }
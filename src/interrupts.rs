@@ -0,0 +1,117 @@
+/// Request to interrupt the program, with the entry of the interrupt vector table to jump to and the priority level
+/// of the interrupt.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InterruptRequest {
+    pub vector: u16,
+    pub priority: u16,
+}
+
+/// Interrupt raised by the keyboard when a key is ready and interrupts are enabled in KBSR.
+pub const KEYBOARD_INTERRUPT: InterruptRequest = InterruptRequest {
+    vector: 0x80,
+    priority: 4,
+};
+
+/// Interrupt raised by the display when it's ready for the next character and interrupts are enabled in DSR.
+pub const DISPLAY_INTERRUPT: InterruptRequest = InterruptRequest {
+    vector: 0x81,
+    priority: 4,
+};
+
+/// Interrupt raised by the timer when its interval elapses and interrupts are enabled in the timer status register.
+pub const TIMER_INTERRUPT: InterruptRequest = InterruptRequest {
+    vector: 0x82,
+    priority: 6,
+};
+
+/// Chooses which interrupt the processor has to service between instructions.
+///
+/// Device interrupts are asserted by the devices on every cycle while their condition holds, and requests raised
+/// with [`InterruptController::raise`] stay pending until they are serviced.
+#[derive(Default)]
+pub struct InterruptController {
+    raised: Vec<InterruptRequest>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self { raised: Vec::new() }
+    }
+
+    /// Raises an interrupt that stays pending until it's serviced.
+    pub fn raise(&mut self, request: InterruptRequest) {
+        self.raised.push(request);
+    }
+
+    /// Returns the raised requests that weren't serviced yet.
+    pub fn pending(&self) -> &[InterruptRequest] {
+        &self.raised
+    }
+
+    /// Returns the pending request with the highest priority among the raised ones and the ones asserted by the
+    /// devices, only if its priority is higher than the priority the processor is running at.
+    /// A raised request that is returned stops being pending.
+    pub fn next_request(
+        &mut self,
+        device_requests: &[InterruptRequest],
+        priority: u16,
+    ) -> Option<InterruptRequest> {
+        let highest_device = device_requests
+            .iter()
+            .copied()
+            .max_by_key(|request| request.priority);
+        let highest_raised = self
+            .raised
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, request)| request.priority)
+            .map(|(index, request)| (index, *request));
+        let raised_wins = match (highest_device, highest_raised) {
+            (Some(device), Some((_, raised))) => raised.priority > device.priority,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        match highest_raised {
+            Some((index, raised)) if raised_wins => {
+                if raised.priority <= priority {
+                    return None;
+                }
+                self.raised.remove(index);
+                Some(raised)
+            }
+            _ => highest_device.filter(|device| device.priority > priority),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_higher_priority_than_the_processor() {
+        let mut controller = InterruptController::new();
+        assert_eq!(
+            Some(KEYBOARD_INTERRUPT),
+            controller.next_request(&[KEYBOARD_INTERRUPT], 3)
+        );
+        assert_eq!(None, controller.next_request(&[KEYBOARD_INTERRUPT], 4));
+        assert_eq!(None, controller.next_request(&[], 0));
+    }
+
+    #[test]
+    fn raised_requests_are_serviced_once_by_priority() {
+        let mut controller = InterruptController::new();
+        controller.raise(TIMER_INTERRUPT);
+        assert_eq!(None, controller.next_request(&[KEYBOARD_INTERRUPT], 7));
+        assert_eq!(
+            Some(TIMER_INTERRUPT),
+            controller.next_request(&[KEYBOARD_INTERRUPT], 0)
+        );
+        assert_eq!(
+            Some(KEYBOARD_INTERRUPT),
+            controller.next_request(&[KEYBOARD_INTERRUPT], 0)
+        );
+        assert_eq!(None, controller.next_request(&[], 0));
+    }
+}
@@ -1,26 +1,74 @@
-use console::Term;
-use raw_tty::GuardMode;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::io::Write;
-use std::time::Duration;
-use std::{char, io};
+use std::time::{Duration, Instant};
 use termios::Termios;
-use timeout_readwrite::TimeoutReader;
 
+use crate::block_engine::BlockCache;
+use crate::builder::LC3VirtualMachineBuilder;
+use crate::call_stack::{Backtrace, CallStack};
+use crate::console::{ConsoleDevice, StdioConsole};
+use crate::coverage::Coverage;
+use crate::decode_cache::{CachedInstruction, DecodeCache};
 use crate::hardware::{
-    DecodedInstruction, Flags, HardwareError, Instruction, MemoryMappedRegisters, Register,
-    TrapCode,
+    ExceptionVector, ExecutionEngine, Flags, HardwareError, INTERRUPT_VECTOR_TABLE, Instruction,
+    MCR_CLOCK_ENABLE, MemoryMappedRegisters, Privilege, PsrMask, Register, StatusBits, TrapCode,
+    TrapMode, is_privileged_address,
 };
+use crate::interrupts::{
+    DISPLAY_INTERRUPT, InterruptController, InterruptRequest, KEYBOARD_INTERRUPT,
+};
+use crate::os::os_image;
+use crate::profiler::Profiler;
+use crate::replay::{InputLog, InputReplay};
+use crate::symbols::SymbolTable;
+use crate::timer::Timer;
+use crate::trace::Tracer;
 
 pub struct LC3VirtualMachine {
     pub memory: [u16; 1 << 16], /* 65536 locations */
     pub registers: [u16; 10],
+    /// Clock enable, mapped to bit 15 of the machine control register.
     pub running: bool,
     pub origin: u16,
+    /// Privilege and priority bits of the PSR. The condition codes are kept in the COND register.
+    psr: u16,
+    /// Supervisor stack pointer, saved while running in user mode.
+    pub saved_ssp: u16,
+    /// User stack pointer, saved while running in supervisor mode.
+    pub saved_usp: u16,
+    pub(crate) interrupts: InterruptController,
+    console: Box<dyn ConsoleDevice>,
+    trap_mode: TrapMode,
+    pub(crate) timer: Timer,
+    /// Number of instructions executed, counting the one being executed.
+    pub instruction_count: u64,
+    recording: Option<InputLog>,
+    replay: Option<InputReplay>,
+    /// Number of instructions after which [`LC3VirtualMachine::run`] stops the program.
+    instruction_limit: Option<u64>,
+    /// Time after which [`LC3VirtualMachine::run`] stops the program, counted from the start of the run.
+    timeout: Option<Duration>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    /// Calls made by the program that haven't returned yet.
+    call_stack: CallStack,
+    /// Address of the instruction executed last, or being executed.
+    instruction_address: Option<u16>,
+    /// Labels of the loaded images, used to describe addresses in reports.
+    symbols: SymbolTable,
+    /// Instructions already decoded, or None if every instruction is decoded when it's fetched.
+    decode_cache: Option<DecodeCache>,
+    engine: ExecutionEngine,
+    blocks: BlockCache,
+    /// Set when the running block has to stop because the last instruction changed code or wrote a device register.
+    block_interrupted: bool,
 }
 
+/// How many instructions are executed between checks of the timeout, so the clock isn't read on every cycle.
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
 #[derive(PartialEq, Debug)]
 pub enum VMError {
     FailedToLoadImage(String),
@@ -29,6 +77,16 @@ pub enum VMError {
     InvalidTrapCode(HardwareError),
     TerminalError(String),
     InvalidAddress(u16),
+    AccessControlViolation(u16),
+    PrivilegeModeViolation(u16),
+    InvalidSnapshot(String),
+    InvalidInputLog(String),
+    InvalidTestSuite(String),
+    InvalidSymbolFile(String),
+    InvalidDebugInfo(String),
+    EndOfInput,
+    InstructionLimitExceeded(u16, u64), /* Last instruction executed and instruction count when the limit was reached */
+    Timeout(u16, u64), /* Last instruction executed and instruction count when the time ran out */
 }
 
 impl fmt::Display for VMError {
@@ -36,29 +94,248 @@ impl fmt::Display for VMError {
         let description = match self {
             VMError::FailedToLoadImage(value) => &format!("Failed to load image: {:?}", value),
             VMError::InvalidInstruction(hardware_error) => {
-                &format!("Invalid Instruction: {:?}", hardware_error.fmt(f))
+                &format!("Invalid Instruction: {}", hardware_error)
             }
             VMError::IOError(value) => &format!("IO Error: {:?}", value),
             VMError::InvalidTrapCode(hardware_error) => {
-                &format!("Invalid Instruction: {:?}", hardware_error.fmt(f))
+                &format!("Invalid Instruction: {}", hardware_error)
             }
             VMError::TerminalError(value) => &format!("Terminal Error: {:?}", value),
             VMError::InvalidAddress(value) => &format!("Invalid Address: {}", value),
+            VMError::AccessControlViolation(value) => {
+                &format!("Access Control Violation at address: x{:04X}", value)
+            }
+            VMError::PrivilegeModeViolation(value) => {
+                &format!("Privilege Mode Violation at address: x{:04X}", value)
+            }
+            VMError::InvalidSnapshot(value) => &format!("Invalid snapshot: {:?}", value),
+            VMError::InvalidInputLog(value) => &format!("Invalid input log: {:?}", value),
+            VMError::InvalidTestSuite(value) => &format!("Invalid test suite: {}", value),
+            VMError::InvalidSymbolFile(value) => &format!("Invalid symbol file: {}", value),
+            VMError::InvalidDebugInfo(value) => &format!("Invalid debug info: {}", value),
+            VMError::EndOfInput => &String::from("End of input"),
+            VMError::InstructionLimitExceeded(pc, count) => &format!(
+                "Instruction limit exceeded after {} instructions at x{:04X}",
+                count, pc
+            ),
+            VMError::Timeout(pc, count) => {
+                &format!("Timed out after {} instructions at x{:04X}", count, pc)
+            }
         };
         f.write_str(description)
     }
 }
 
+impl std::error::Error for VMError {}
+
+impl Default for LC3VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LC3VirtualMachine {
+    /// Creates a vm in supervisor mode with the default OS loaded for the trap routines and R6 pointing to the
+    /// supervisor stack. Every other register and the rest of the memory start at zero. Programs loaded with the
+    /// builder run in user mode instead, so the trap routines don't use their stack.
     pub fn new() -> Self {
+        let mut vm = Self::blank();
+        read_image_file(&mut vm, os_image()).expect("The default OS image loads");
+        vm.registers[Register::R6] = vm.saved_ssp;
+        vm
+    }
+
+    /// Creates a vm with nothing in memory but the device registers, which the builder configures.
+    pub(crate) fn blank() -> Self {
+        let mut memory = [0; 1 << 16];
+        // The display starts ready to receive a character.
+        memory[MemoryMappedRegisters::MrDSR as usize] = StatusBits::Ready as u16;
         Self {
-            memory: [0; 1 << 16],
+            memory,
             registers: [0; 10],
             running: false,
             origin: 0x3000,
+            psr: 0, // Supervisor mode with priority 0
+            saved_ssp: 0x3000,
+            saved_usp: 0xFE00,
+            interrupts: InterruptController::new(),
+            console: Box::new(StdioConsole::new()),
+            trap_mode: TrapMode::default(),
+            timer: Timer::new(),
+            instruction_count: 0,
+            recording: None,
+            replay: None,
+            instruction_limit: None,
+            timeout: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            call_stack: CallStack::new(),
+            instruction_address: None,
+            symbols: SymbolTable::new(),
+            decode_cache: Some(DecodeCache::new()),
+            engine: ExecutionEngine::Interpreter,
+            blocks: BlockCache::new(),
+            block_interrupted: false,
+        }
+    }
+
+    /// Replaces the console used for input and output.
+    pub fn set_console(&mut self, console: Box<dyn ConsoleDevice>) {
+        self.console = console;
+    }
+
+    /// Returns the console used for input and output.
+    pub fn console_mut(&mut self) -> &mut dyn ConsoleDevice {
+        self.console.as_mut()
+    }
+
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    /// Sets how TRAP instructions run the service routines. In native mode the routines are implemented by the vm,
+    /// in memory resident mode the routines have to be loaded into memory, for example with the default OS image.
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Starts recording every input byte the program consumes, with the instruction count when it was consumed.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputLog::new());
+    }
+
+    /// Stops recording input and returns what was recorded.
+    pub fn take_recording(&mut self) -> Option<InputLog> {
+        self.recording.take()
+    }
+
+    /// Feeds the input in log to the program, at the same instruction counts it was recorded. Once the log is
+    /// exhausted the input comes from the console again.
+    pub fn replay_input(&mut self, log: InputLog) {
+        self.replay = Some(InputReplay::new(log));
+    }
+
+    /// Limits the number of instructions the vm executes, counting the ones already executed. Once the limit is
+    /// reached [`LC3VirtualMachine::run`] fails with [`VMError::InstructionLimitExceeded`].
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    /// Limits the time each call to [`LC3VirtualMachine::run`] can take. Once it runs out the run fails with
+    /// [`VMError::Timeout`]. The deadline is only checked every 1024 instructions, and a program waiting for input that
+    /// never arrives isn't stopped, since reading the console blocks.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Attaches a tracer that records every instruction executed, or detaches it with None.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Detaches the tracer and returns it, so it can be flushed.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// Returns the backtrace of the instruction executed last, which is the one that failed when
+    /// [`LC3VirtualMachine::run`] returns an error, with the addresses described by the symbols of the vm.
+    pub fn backtrace(&self) -> Backtrace {
+        let address = self
+            .instruction_address
+            .unwrap_or(self.registers[Register::PC]);
+        self.call_stack.backtrace(address, &self.symbols)
+    }
+
+    /// Attaches a profiler that counts the instructions executed, or detaches it with None.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Detaches the profiler and returns it, so its report can be printed.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    /// Attaches a coverage that records the instructions executed and the branches taken, or detaches it with None.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    /// Detaches the coverage and returns it, so it can be reported or attached to another vm.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Enables or disables caching decoded instructions by address. It's enabled by default.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled.then(DecodeCache::new);
+    }
+
+    pub fn engine(&self) -> ExecutionEngine {
+        self.engine
+    }
+
+    /// Sets how [`LC3VirtualMachine::run`] executes the program. Single steps always run in the interpreter.
+    pub fn set_engine(&mut self, engine: ExecutionEngine) {
+        self.engine = engine;
+        self.blocks.clear();
+    }
+
+    /// Reads a byte of input, waiting until there's one.
+    fn read_input(&mut self) -> Result<u8, VMError> {
+        let byte = match self.replay.as_mut().and_then(|replay| replay.next_byte()) {
+            Some(byte) => byte,
+            None => self.console.read_byte()?,
+        };
+        self.record_input(byte);
+        Ok(byte)
+    }
+
+    /// Returns the key being pressed, if there's any.
+    fn poll_input(&mut self) -> Result<Option<u8>, VMError> {
+        let key = match self.replay.as_mut() {
+            Some(replay) if !replay.is_finished() => replay.poll(self.instruction_count),
+            _ => self.console.poll_key()?,
+        };
+        if let Some(byte) = key {
+            self.record_input(byte);
+        }
+        Ok(key)
+    }
+
+    fn record_input(&mut self, byte: u8) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.record(self.instruction_count, byte);
         }
     }
 
+    /// Returns a builder to configure the vm before running it.
+    pub fn builder() -> LC3VirtualMachineBuilder {
+        LC3VirtualMachineBuilder::new()
+    }
+
     fn mem_available_space(&self) -> usize {
         self.memory.len() - self.origin as usize
     }
@@ -71,58 +348,449 @@ impl LC3VirtualMachine {
         self.registers[Register::COND] = 1;
     }
 
-    fn mem_write(&mut self, address: u16, value: u16) -> Result<(), VMError> {
+    /// Returns the processor status register: privilege (bit 15), priority (bits 10 to 8) and the condition codes
+    /// (bits 2 to 0).
+    pub fn psr(&self) -> u16 {
+        self.psr | (self.registers[Register::COND] & PsrMask::Cond as u16)
+    }
+
+    pub fn set_psr(&mut self, value: u16) {
+        self.psr = value & (PsrMask::Privilege as u16 | PsrMask::Priority as u16);
+        self.registers[Register::COND] = value & PsrMask::Cond as u16;
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        if self.psr & PsrMask::Privilege as u16 == 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        }
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.psr = (self.psr & !(PsrMask::Privilege as u16)) | (privilege as u16) << 15;
+    }
+
+    /// Returns the priority level the processor is running at, from 0 to 7.
+    pub fn priority(&self) -> u16 {
+        (self.psr & PsrMask::Priority as u16) >> 8
+    }
+
+    pub fn set_priority(&mut self, priority: u16) {
+        self.psr = (self.psr & !(PsrMask::Priority as u16)) | (priority & 0x7) << 8;
+    }
+
+    /// Returns the machine control register, with the clock enable bit (bit 15) set while the vm is running.
+    pub fn mcr(&self) -> u16 {
+        let clock_enable = if self.running { MCR_CLOCK_ENABLE } else { 0 };
+        self.memory[MemoryMappedRegisters::MrMCR as usize] | clock_enable
+    }
+
+    /// Checks that the address can be accessed with the current privilege mode.
+    fn check_access(&self, address: u16) -> Result<(), VMError> {
+        if self.privilege() == Privilege::User && is_privileged_address(address) {
+            return Err(VMError::AccessControlViolation(address));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mem_write(&mut self, address: u16, value: u16) -> Result<(), VMError> {
         if address as usize > self.memory.len() {
             return Err(VMError::InvalidAddress(address));
         }
+        self.check_access(address)?;
+        self.trace_write(address, value);
+        self.write_word(address, value)
+    }
+
+    /// Records a write in the trace, for the writes made without mem_write too, like the ones of the stack.
+    fn trace_write(&mut self, address: u16, value: u16) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record_write(address, value);
+        }
+    }
+
+    /// Records a read in the trace, for the reads made without mem_read too, like the ones of the stack and of the
+    /// native trap routines.
+    fn trace_read(&mut self, address: u16, value: u16) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record_read(address, value);
+        }
+    }
+
+    /// Writes a word to memory or to a device register with its side effects, whatever the privilege mode. The
+    /// debuggers write memory through it, so they can write anywhere.
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) -> Result<(), VMError> {
+        if let Some(decode_cache) = self.decode_cache.as_mut() {
+            decode_cache.invalidate(address);
+        }
+        if self.blocks.contains(address) {
+            self.blocks.clear();
+            self.block_interrupted = true;
+        } else if address >= MemoryMappedRegisters::MrKBSR as u16 {
+            self.block_interrupted = true;
+        }
+        if address == MemoryMappedRegisters::MrPSR as u16 {
+            self.set_psr(value);
+        } else if address == MemoryMappedRegisters::MrKBSR as u16
+            || address == MemoryMappedRegisters::MrDSR as u16
+        {
+            // Only the interrupt enable bit can be written, the ready bit is set by the device.
+            let ready = self.memory[address as usize] & StatusBits::Ready as u16;
+            self.memory[address as usize] = ready | (value & StatusBits::InterruptEnable as u16);
+            return Ok(());
+        } else if address == MemoryMappedRegisters::MrDDR as u16 {
+            self.memory[address as usize] = value;
+            self.write_to_display(value as u8)?;
+            self.console.flush()?;
+            return Ok(());
+        } else if address == MemoryMappedRegisters::MrTSR as u16 {
+            self.timer.write_status(value);
+            return Ok(());
+        } else if address == MemoryMappedRegisters::MrTIR as u16 {
+            self.timer.set_interval(value);
+            return Ok(());
+        } else if address == MemoryMappedRegisters::MrTCR as u16 {
+            self.timer.set_count(value);
+            return Ok(());
+        } else if address == MemoryMappedRegisters::MrMCR as u16 {
+            // Clearing the clock enable bit stops the vm once the instruction that writes it finishes.
+            self.running = value & MCR_CLOCK_ENABLE != 0;
+            self.memory[address as usize] = value & !MCR_CLOCK_ENABLE;
+            return Ok(());
+        }
         self.memory[address as usize] = value;
         Ok(())
     }
 
-    fn mem_read(&mut self, address: u16) -> Result<u16, VMError> {
+    pub(crate) fn mem_read(&mut self, address: u16) -> Result<u16, VMError> {
+        let value = self.read_word(address)?;
+        self.trace_read(address, value);
+        Ok(value)
+    }
+
+    /// Reads a word from memory or from a device register.
+    fn read_word(&mut self, address: u16) -> Result<u16, VMError> {
+        self.check_access(address)?;
+        if address == MemoryMappedRegisters::MrPSR as u16 {
+            return Ok(self.psr());
+        }
+        if address == MemoryMappedRegisters::MrMCR as u16 {
+            return Ok(self.mcr());
+        }
+        if address == MemoryMappedRegisters::MrTSR as u16 {
+            return Ok(self.timer.read_status());
+        }
+        if address == MemoryMappedRegisters::MrTIR as u16 {
+            return Ok(self.timer.interval());
+        }
+        if address == MemoryMappedRegisters::MrTCR as u16 {
+            return Ok(self.timer.count());
+        }
         if address == MemoryMappedRegisters::MrKBSR as u16 {
-            let mut stdin = std::io::stdin()
-                .guard_mode()
-                .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
-            let mut input_buffer = [1; 1];
-            let mut rdr = TimeoutReader::new(&mut *stdin, Duration::from_millis(50000));
-            rdr.read_exact(&mut input_buffer)
-                .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
-            if input_buffer[0] != 0 {
-                // If any key is being pressed
-                self.memory[MemoryMappedRegisters::MrKBSR as usize] = 1 << 15;
-                self.memory[MemoryMappedRegisters::MrKBDR as usize] = input_buffer[0] as u16;
-            } else {
-                self.memory[MemoryMappedRegisters::MrKBSR as usize] = 0;
-            }
+            self.poll_keyboard()?;
+        } else if address == MemoryMappedRegisters::MrKBDR as u16 {
+            // Reading the data clears the ready bit so the keyboard can receive the next key.
+            self.memory[MemoryMappedRegisters::MrKBSR as usize] &= !(StatusBits::Ready as u16);
         }
         Ok(self.memory[address as usize])
     }
 
+    /// If the keyboard has no key ready, checks if any key is being pressed and stores it in KBDR.
+    fn poll_keyboard(&mut self) -> Result<(), VMError> {
+        let status = self.memory[MemoryMappedRegisters::MrKBSR as usize];
+        if status & StatusBits::Ready as u16 == 0
+            && let Some(key) = self.poll_input()?
+        {
+            // If any key is being pressed
+            self.memory[MemoryMappedRegisters::MrKBSR as usize] = status | StatusBits::Ready as u16;
+            self.memory[MemoryMappedRegisters::MrKBDR as usize] = key as u16;
+        }
+        Ok(())
+    }
+
+    /// Sends a character to the display. The display clears its ready bit while it takes the character and sets it
+    /// again once the character is written to the console, which happens before the next instruction runs.
+    fn write_to_display(&mut self, byte: u8) -> Result<(), VMError> {
+        self.memory[MemoryMappedRegisters::MrDSR as usize] &= !(StatusBits::Ready as u16);
+        self.console.write_byte(byte)?;
+        self.memory[MemoryMappedRegisters::MrDSR as usize] |= StatusBits::Ready as u16;
+        Ok(())
+    }
+
+    /// Raises an interrupt that is serviced between instructions, once the processor runs at a lower priority.
+    pub fn raise_interrupt(&mut self, request: InterruptRequest) {
+        self.interrupts.raise(request);
+    }
+
+    /// Returns the interrupts requested by the devices.
+    fn device_interrupt_requests(&mut self) -> Result<Vec<InterruptRequest>, VMError> {
+        let mut requests = Vec::new();
+        if self.memory[MemoryMappedRegisters::MrKBSR as usize] & StatusBits::InterruptEnable as u16
+            != 0
+        {
+            match self.poll_keyboard() {
+                // The program isn't reading the keyboard, so a scripted console that ran out of input has no key.
+                Ok(()) | Err(VMError::EndOfInput) => {}
+                Err(error) => return Err(error),
+            }
+            if self.memory[MemoryMappedRegisters::MrKBSR as usize] & StatusBits::Ready as u16 != 0 {
+                requests.push(KEYBOARD_INTERRUPT);
+            }
+        }
+        let display_status = self.memory[MemoryMappedRegisters::MrDSR as usize];
+        if display_status & StatusBits::InterruptEnable as u16 != 0
+            && display_status & StatusBits::Ready as u16 != 0
+        {
+            requests.push(DISPLAY_INTERRUPT);
+        }
+        requests.extend(self.timer.interrupt_request());
+        Ok(requests)
+    }
+
+    /// Starts the service routine of the pending interrupt with highest priority, if it has higher priority than
+    /// the program being executed.
+    fn service_interrupts(&mut self) -> Result<(), VMError> {
+        let device_requests = self.device_interrupt_requests()?;
+        if let Some(request) = self
+            .interrupts
+            .next_request(&device_requests, self.priority())
+        {
+            self.initiate_service_routine(request.vector, Some(request.priority));
+        }
+        Ok(())
+    }
+
+    /// Runs the program until it halts, fails or exceeds the instruction limit or the timeout.
     pub fn run(&mut self) -> Result<(), VMError> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
         self.running = true;
         while self.running {
-            let instruction_u16 = self.mem_read(self.registers[Register::PC])?; // Read Instruction from memory
-            self.registers[Register::PC] = self.registers[Register::PC].wrapping_add(1); // PC + 1
-            self.execute_instruction(instruction_u16)?;
+            self.check_limits(deadline)?;
+            match self.engine {
+                ExecutionEngine::Interpreter => self.step()?,
+                ExecutionEngine::BasicBlock => self.run_block(deadline)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if any interrupt could be requested before the next instruction: a pending request or a device with
+    /// interrupts enabled. Only writes to the device registers can enable them.
+    fn interrupts_possible(&self) -> bool {
+        let enabled = |status: u16| status & StatusBits::InterruptEnable as u16 != 0;
+        !self.interrupts.pending().is_empty()
+            || enabled(self.memory[MemoryMappedRegisters::MrKBSR as usize])
+            || enabled(self.memory[MemoryMappedRegisters::MrDSR as usize])
+            || enabled(self.timer.status())
+    }
+
+    /// Runs the compiled block that starts at the PC. Every instruction of the block counts as a step, but the
+    /// interrupts aren't checked since none can be requested while the block runs. The vm falls back to the
+    /// interpreter for a single step while interrupts are possible, while tracing and for code the processor can't
+    /// fetch.
+    fn run_block(&mut self, deadline: Option<Instant>) -> Result<(), VMError> {
+        let start = self.registers[Register::PC];
+        if self.tracer.is_some()
+            || self.interrupts_possible()
+            || start >= MemoryMappedRegisters::MrKBSR as u16
+            || self.check_access(start).is_err()
+        {
+            return self.step();
+        }
+        let block = self.blocks.get_or_compile(&self.memory, start);
+        self.block_interrupted = false;
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            if index > 0 {
+                self.check_limits(deadline)?;
+            }
+            self.instruction_count += 1;
+            self.timer.tick();
+            let address = start.wrapping_add(index as u16);
+            self.instruction_address = Some(address);
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.record(address, block.words[index]);
+            }
+            let next = address.wrapping_add(1);
+            self.registers[Register::PC] = next;
+            let result = instruction(self);
+            // Only the last instruction of a block can be a call or a return.
+            if self.coverage.is_some() || index + 1 == block.instructions.len() {
+                self.record_control_flow(address, block.words[index]);
+            }
+            self.handle_access_violation(result)?;
+            if self.block_interrupted || !self.running || self.registers[Register::PC] != next {
+                break;
+            }
         }
         Ok(())
     }
 
-    fn execute_instruction(&mut self, instrucction_16: u16) -> Result<(), VMError> {
-        let decoded_instruction = DecodedInstruction::decode_instruction(instrucction_16)
-            .map_err(VMError::InvalidInstruction)?;
-        match Instruction::from_u16(decoded_instruction.op_code)
-            .map_err(VMError::InvalidInstruction)?
+    /// Fails if the next instruction would exceed the instruction limit or if the deadline has passed, reporting the
+    /// last instruction executed like [`LC3VirtualMachine::backtrace`].
+    pub(crate) fn check_limits(&self, deadline: Option<Instant>) -> Result<(), VMError> {
+        let address = self
+            .instruction_address
+            .unwrap_or(self.registers[Register::PC]);
+        if self
+            .instruction_limit
+            .is_some_and(|limit| self.instruction_count >= limit)
         {
+            return Err(VMError::InstructionLimitExceeded(
+                address,
+                self.instruction_count,
+            ));
+        }
+        if let Some(deadline) = deadline
+            && self
+                .instruction_count
+                .is_multiple_of(TIMEOUT_CHECK_INTERVAL)
+            && Instant::now() >= deadline
+        {
+            return Err(VMError::Timeout(address, self.instruction_count));
+        }
+        Ok(())
+    }
+
+    /// Executes exactly one fetch, decode and execute cycle, servicing first any pending interrupt.
+    pub fn step(&mut self) -> Result<(), VMError> {
+        self.instruction_count += 1;
+        self.timer.tick();
+        self.service_interrupts()?;
+        let result = self.fetch_and_execute();
+        self.handle_access_violation(result)
+    }
+
+    /// Turns an access control violation into an exception.
+    fn handle_access_violation(&mut self, result: Result<(), VMError>) -> Result<(), VMError> {
+        match result {
+            Err(VMError::AccessControlViolation(address)) => self.initiate_exception(
+                ExceptionVector::AccessControlViolation,
+                VMError::AccessControlViolation(address),
+            ),
+            result => result,
+        }
+    }
+
+    fn fetch_and_execute(&mut self) -> Result<(), VMError> {
+        let pc = self.registers[Register::PC];
+        self.instruction_address = Some(pc);
+        let instruction_u16 = self.mem_read(pc)?; // Read Instruction from memory
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, instruction_u16);
+        }
+        self.registers[Register::PC] = self.registers[Register::PC].wrapping_add(1); // PC + 1
+        let instruction = match self.decode_cache.as_mut() {
+            Some(decode_cache) => decode_cache.get_or_decode(pc, instruction_u16)?,
+            None => CachedInstruction::decode(instruction_u16)?,
+        };
+        let result = match self.tracer.as_mut() {
+            Some(tracer) if tracer.traces(pc) => {
+                tracer.begin_instruction();
+                let before = self.registers;
+                let result = self.execute_instruction(instruction);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.end_instruction(
+                        self.instruction_count,
+                        pc,
+                        instruction_u16,
+                        &before,
+                        &self.registers,
+                    )?;
+                }
+                result
+            }
+            _ => self.execute_instruction(instruction),
+        };
+        self.record_control_flow(pc, instruction_u16);
+        result
+    }
+
+    /// Records where the instruction executed at pc left the PC, in the call stack and the coverage.
+    #[inline]
+    fn record_control_flow(&mut self, pc: u16, word: u16) {
+        let next_pc = self.registers[Register::PC];
+        self.call_stack.record(pc, word, next_pc);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(pc, word, next_pc);
+        }
+    }
+
+    /// Pushes a value onto the stack pointed by R6.
+    fn push(&mut self, value: u16) {
+        self.registers[Register::R6] = self.registers[Register::R6].wrapping_sub(1);
+        self.memory[self.registers[Register::R6] as usize] = value;
+        self.trace_write(self.registers[Register::R6], value);
+    }
+
+    /// Pops a value from the stack pointed by R6.
+    fn pop(&mut self) -> u16 {
+        let value = self.memory[self.registers[Register::R6] as usize];
+        self.trace_read(self.registers[Register::R6], value);
+        self.registers[Register::R6] = self.registers[Register::R6].wrapping_add(1);
+        value
+    }
+
+    /// Switches to the supervisor stack if the processor was in user mode and pushes the PSR and the PC onto it, so
+    /// RTI returns to the PC in the previous mode. The priority level is only changed when priority is given.
+    fn enter_supervisor_mode(&mut self, priority: Option<u16>) {
+        let psr = self.psr();
+        if self.privilege() == Privilege::User {
+            self.saved_usp = self.registers[Register::R6];
+            self.registers[Register::R6] = self.saved_ssp;
+        }
+        self.set_privilege(Privilege::Supervisor);
+        if let Some(priority) = priority {
+            self.set_priority(priority);
+        }
+        self.push(psr);
+        self.push(self.registers[Register::PC]);
+    }
+
+    /// Starts an interrupt or exception service routine, entering supervisor mode and jumping to the address stored
+    /// in the interrupt vector table entry of vector. The priority level is only changed when priority is given.
+    fn initiate_service_routine(&mut self, vector: u16, priority: Option<u16>) {
+        let return_address = self.registers[Register::PC];
+        // Interrupts happen between instructions, exceptions during the instruction that raised them.
+        let call_site = match priority {
+            Some(_) => return_address,
+            None => self.instruction_address.unwrap_or(return_address),
+        };
+        self.enter_supervisor_mode(priority);
+        self.registers[Register::PC] =
+            self.memory[INTERRUPT_VECTOR_TABLE.wrapping_add(vector) as usize];
+        self.call_stack.record_interrupt(
+            vector as u8,
+            call_site,
+            self.registers[Register::PC],
+            return_address,
+        );
+    }
+
+    /// Starts the service routine of an exception. If there's no routine installed for the exception in the
+    /// interrupt vector table, the error is returned instead.
+    fn initiate_exception(
+        &mut self,
+        vector: ExceptionVector,
+        error: VMError,
+    ) -> Result<(), VMError> {
+        if self.memory[INTERRUPT_VECTOR_TABLE.wrapping_add(vector as u16) as usize] == 0 {
+            return Err(error);
+        }
+        self.initiate_service_routine(vector as u16, None);
+        Ok(())
+    }
+
+    pub(crate) fn execute_instruction(
+        &mut self,
+        instruction: CachedInstruction,
+    ) -> Result<(), VMError> {
+        let decoded_instruction = instruction.decoded;
+        match instruction.instruction {
             Instruction::OpBR =>
             /* branch */
             {
-                self.branch(
-                    Flags::from_u16(decoded_instruction.flags)
-                        .map_err(VMError::InvalidInstruction)?,
-                    decoded_instruction.imm9,
-                );
+                self.branch(instruction.flags, decoded_instruction.imm9);
                 Ok(())
             }
             Instruction::OpADD =>
@@ -216,6 +884,19 @@ impl LC3VirtualMachine {
                 );
                 Ok(())
             }
+            Instruction::OpRTI => {
+                /* return from interrupt */
+                self.return_from_interrupt()
+            }
+            Instruction::OpRES => {
+                /* reserved */
+                self.initiate_exception(
+                    ExceptionVector::IllegalOpcode,
+                    VMError::InvalidInstruction(HardwareError::InvalidInstruction(
+                        decoded_instruction.op_code,
+                    )),
+                )
+            }
             Instruction::OpLEA => {
                 /* load effective address */
                 self.load_effective_address(decoded_instruction.dst, decoded_instruction.imm9);
@@ -224,11 +905,18 @@ impl LC3VirtualMachine {
             Instruction::OpTRAP => {
                 /* execute trap */
                 self.registers[Register::R7] = self.registers[Register::PC];
-                self.execute_trap_routine(
-                    TrapCode::from_u16(decoded_instruction.trapvect8)
-                        .map_err(VMError::InvalidTrapCode)?,
-                )?;
-                self.registers[Register::PC] = self.registers[Register::R7];
+                match self.trap_mode {
+                    TrapMode::Native => {
+                        self.execute_trap_routine(
+                            TrapCode::from_u16(decoded_instruction.trapvect8)
+                                .map_err(VMError::InvalidTrapCode)?,
+                        )?;
+                        self.registers[Register::PC] = self.registers[Register::R7];
+                    }
+                    TrapMode::MemoryResident => {
+                        self.jump_to_trap_routine(decoded_instruction.trapvect8)?
+                    }
+                }
                 Ok(())
             }
         }
@@ -241,13 +929,25 @@ impl LC3VirtualMachine {
             TrapCode::Out => self.trap_out(),
             TrapCode::Puts => self.trap_puts(),
             TrapCode::Putsp => self.trap_putsp(),
-            TrapCode::Halt => {
-                self.trap_halt();
-                Ok(())
-            }
+            TrapCode::Halt => self.trap_halt(),
         }
     }
 
+    /// Enters supervisor mode and jumps to the service routine stored in the trap vector table entry of
+    /// trap_vector, which returns with RTI. An empty entry means no routine is loaded for the trap vector.
+    fn jump_to_trap_routine(&mut self, trap_vector: u16) -> Result<(), VMError> {
+        let routine_address = self.memory[trap_vector as usize];
+        self.trace_read(trap_vector, routine_address);
+        if routine_address == 0 {
+            return Err(VMError::InvalidTrapCode(HardwareError::InvalidTrapCode(
+                trap_vector,
+            )));
+        }
+        self.enter_supervisor_mode(None);
+        self.registers[Register::PC] = routine_address;
+        Ok(())
+    }
+
     /// Checks if a determined flag is on.
     fn flag_is_on(&self, flag: Flags) -> bool {
         match flag {
@@ -263,7 +963,7 @@ impl LC3VirtualMachine {
     }
 
     /// Only one flag at a time is on.
-    fn update_flags(&mut self, result_from_operation: u16) {
+    pub(crate) fn update_flags(&mut self, result_from_operation: u16) {
         if result_from_operation == 0 {
             self.registers[Register::COND] = 2; // Flag Zro 0b10
         } else if result_from_operation & 0x8000 == 0 {
@@ -310,7 +1010,7 @@ impl LC3VirtualMachine {
     fn load(&mut self, dst: Register, pc_offset: u16) -> Result<(), VMError> {
         let mem_adress = self.registers[Register::PC].wrapping_add(self.extend_sign(pc_offset, 9));
         self.registers[dst] = self.mem_read(mem_adress)?;
-        self.update_flags(self.memory[mem_adress as usize]);
+        self.update_flags(self.registers[dst]);
         Ok(())
     }
 
@@ -386,15 +1086,16 @@ impl LC3VirtualMachine {
 
         let mem_adress = self.mem_read(self.registers[Register::PC].wrapping_add(pc_offset_u16))?;
         self.registers[dst] = self.mem_read(mem_adress)?;
-        self.update_flags(self.memory[mem_adress as usize]);
+        self.update_flags(self.registers[dst]);
         Ok(())
     }
 
     /// Store Indirect instruction stores in memory the content in the src register.
     /// The memory address to store de value is obtained from the memory position in address pc + pc_offset (9 bit immediate).
     fn store_indirect(&mut self, src: Register, pc_offset: u16) -> Result<(), VMError> {
-        let memory_address = self.memory
-            [self.registers[Register::PC].wrapping_add(self.extend_sign(pc_offset, 9)) as usize];
+        let pc_offset_u16 = self.extend_sign(pc_offset, 9);
+        let memory_address =
+            self.mem_read(self.registers[Register::PC].wrapping_add(pc_offset_u16))?;
         self.mem_write(memory_address, self.registers[src])?;
         Ok(())
     }
@@ -412,63 +1113,85 @@ impl LC3VirtualMachine {
         self.update_flags(effective_adress);
     }
 
-    /// Writes in stdout string stored in memory address in R0. Each address stores one char.
+    /// Return from interrupt restores the PC and the PSR saved in the supervisor stack when the service routine
+    /// started. If the restored PSR is in user mode the user stack is restored too.
+    /// Executing it in user mode raises a privilege mode violation exception.
+    fn return_from_interrupt(&mut self) -> Result<(), VMError> {
+        if self.privilege() == Privilege::User {
+            let address = self.registers[Register::PC].wrapping_sub(1);
+            return self.initiate_exception(
+                ExceptionVector::PrivilegeModeViolation,
+                VMError::PrivilegeModeViolation(address),
+            );
+        }
+        self.registers[Register::PC] = self.pop();
+        let psr = self.pop();
+        self.set_psr(psr);
+        if self.privilege() == Privilege::User {
+            self.saved_ssp = self.registers[Register::R6];
+            self.registers[Register::R6] = self.saved_usp;
+        }
+        Ok(())
+    }
+
+    /// Writes in the console string stored in memory address in R0. Each address stores one char.
     fn trap_puts(&mut self) -> Result<(), VMError> {
-        let mut term = Term::stdout();
         let mut character_address_in_memory = self.registers[Register::R0] as usize;
         while self.memory[character_address_in_memory] != 0 {
-            let char_to_write = self.memory[character_address_in_memory] as u8 as char;
-            putchar(&mut term, char_to_write)?;
+            let word = self.memory[character_address_in_memory];
+            self.trace_read(character_address_in_memory as u16, word);
+            let char_to_write = word as u8;
+            self.write_to_display(char_to_write)?;
             character_address_in_memory += 1;
         }
-        term.flush()
-            .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+        self.trace_read(character_address_in_memory as u16, 0);
+        self.console.flush()?;
         Ok(())
     }
 
     /// Stores input character in R0.
     fn trap_getc(&mut self) -> Result<(), VMError> {
-        let read_byte = getchar().map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+        let read_byte = self.read_input()?;
         self.registers[Register::R0] = read_byte as u16;
         Ok(())
     }
 
-    /// Writes in stdout the char in store in R0.
+    /// Writes in the console the char in store in R0.
     fn trap_out(&mut self) -> Result<(), VMError> {
-        let mut term = Term::stdout();
-        let char_to_write = self.registers[Register::R0] as u8 as char;
-        putchar(&mut term, char_to_write)?;
-        term.flush()
-            .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+        let char_to_write = self.registers[Register::R0] as u8;
+        self.write_to_display(char_to_write)?;
+        self.console.flush()?;
         Ok(())
     }
 
-    /// Reads a character written in stdin, then writes it in stdout and stores it in R0.
+    /// Reads a character written in the console, then writes it back to the console and stores it in R0.
     fn trap_in(&mut self) -> Result<(), VMError> {
-        println!("Enter a character: ");
-        let read_char = getchar()?;
-        let mut term = Term::stdout();
-        putchar(&mut term, read_char)?;
-        term.flush()
-            .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+        for byte in b"Enter a character: \n" {
+            self.write_to_display(*byte)?;
+        }
+        self.console.flush()?;
+        let read_char = self.read_input()?;
+        self.write_to_display(read_char)?;
+        self.console.flush()?;
         self.registers[Register::R0] = read_char as u16;
         self.update_flags(read_char as u16);
         Ok(())
     }
 
-    /// Writes in stdout the stored in memory address in R0. Each address stores 4 chars in little endian format.
+    /// Writes in the console the stored in memory address in R0. Each address stores 4 chars in little endian format.
     fn trap_putsp(&mut self) -> Result<(), VMError> {
-        let mut term = Term::stdout();
         let mut character_address_in_memory = self.registers[Register::R0] as usize;
         while (self.memory[character_address_in_memory]) != 0
             || (self.memory[character_address_in_memory]) != 3
         {
-            let chars_to_write = self.memory[character_address_in_memory].to_le_bytes();
+            let word = self.memory[character_address_in_memory];
+            self.trace_read(character_address_in_memory as u16, word);
+            let chars_to_write = word.to_le_bytes();
             // Turns two chars read from a word as little endian format into big endian format. Since chars are
             // already little  endian to turn them to the other format it's necesary to apply to_le_bytes() because
             // this is the function that makes the bytes interchange places.
             for char in chars_to_write {
-                putchar(&mut term, char as char)?;
+                self.write_to_display(char)?;
             }
             if (self.memory[character_address_in_memory] & 0xFF00) == 0
                 || (self.memory[character_address_in_memory] & 0xFF00) == 0x0300
@@ -480,35 +1203,22 @@ impl LC3VirtualMachine {
             }
             character_address_in_memory += 1;
         }
-        term.flush()
-            .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+        self.console.flush()?;
         Ok(())
     }
 
-    fn trap_halt(&mut self) {
-        Term::stdout().flush().expect("Stdout error");
+    /// Stops the clock, as clearing the clock enable bit of the MCR does.
+    fn trap_halt(&mut self) -> Result<(), VMError> {
+        self.console.flush()?;
         self.running = false;
+        Ok(())
     }
 }
 
-/// Reads input character from stdin.
-fn getchar() -> Result<char, VMError> {
-    let mut term = io::stdin();
-    let mut buff: [u8; 1] = [0; 1];
-    term.read(&mut buff)
-        .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
-    Ok(buff[0] as char)
-}
-
-/// Writes character in stdout.
-fn putchar(term: &mut Term, char_to_write: char) -> Result<(), VMError> {
-    term.write_all(&[char_to_write as u8])
-        .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
-    Ok(())
-}
-
+/// Loads into the vm memory the image in the file at img_file_path.
 pub fn read_image(vm: &mut LC3VirtualMachine, img_file_path: &str) -> Result<(), VMError> {
-    let mut image = File::open(img_file_path).unwrap();
+    let mut image = File::open(img_file_path)
+        .map_err(|error| VMError::FailedToLoadImage(format!("{:?}", error)))?;
     let mut buffer: Vec<u8> = Vec::new();
     image
         .read_to_end(&mut buffer)
@@ -517,6 +1227,8 @@ pub fn read_image(vm: &mut LC3VirtualMachine, img_file_path: &str) -> Result<(),
     Ok(())
 }
 
+/// Loads into the vm memory an image already read into a buffer. The first word of the image is the address where
+/// the rest of the image is loaded, and every word is stored in big endian format.
 pub fn read_image_file(
     vm: &mut LC3VirtualMachine,
     image_in_buffer: Vec<u8>,
@@ -524,7 +1236,7 @@ pub fn read_image_file(
     // Image as vec<u8> has to have even length to convert to u16 words.
     // Image with length smaller than 2 is an invalid image
     // Image has to fit in memory space starting at origin address.
-    if image_in_buffer.len() % 2 != 0
+    if !image_in_buffer.len().is_multiple_of(2)
         || image_in_buffer.len() < 2
         || image_in_buffer.len() / 2 > vm.mem_available_space()
     {
@@ -564,6 +1276,8 @@ pub fn restore_input_buffering(original_tio: &mut Termios) -> Result<(), VMError
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::assemble;
+    use crate::console::{BufferConsole, ScriptedConsole};
     #[test]
     fn index_and_index_mut_with_registers() {
         let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
@@ -904,7 +1618,7 @@ mod tests {
         vm.registers[Register::R1] = 32;
         vm.registers[Register::R2] = 5;
         let instruction = 0b0001000001000010; //ADD r0, r1, r2
-        let _ = vm.execute_instruction(instruction);
+        let _ = vm.execute_instruction(CachedInstruction::decode(instruction).unwrap());
         assert_eq!(vm.registers[Register::R0], 37);
         assert_eq!(vm.registers[Register::COND], 1); // Check Pos flag. 
     }
@@ -923,6 +1637,7 @@ mod tests {
     #[test]
     fn reding_image_file_with_add_and_trap() {
         let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.set_trap_mode(TrapMode::Native);
         vm.origin = 0x00;
         // vector has two first elements as address to load image, then the two following elements are instruction ADD r0, r1, r2
         // and two last elements are instruction TRAP HALT
@@ -937,6 +1652,240 @@ mod tests {
         assert!(!vm.running);
     }
 
+    #[test]
+    fn step_executes_one_instruction() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.origin = 0x00;
+        // Image with ADD r0, r1, r2 twice and TRAP HALT
+        let image_file = vec![0x00, 0x00, 0x10, 0x42, 0x10, 0x42, 0xF0, 0x25];
+        vm.registers[Register::R1] = 32;
+        vm.registers[Register::R2] = 5;
+        assert_eq!(Ok(()), read_image_file(&mut vm, image_file));
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 1);
+        assert_eq!(vm.registers[Register::R0], 37);
+        vm.registers[Register::R1] = 1;
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 2);
+        assert_eq!(vm.registers[Register::R0], 6);
+    }
+
+    #[test]
+    fn processor_status_register_is_memory_mapped() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.registers[Register::COND] = 2;
+        assert_eq!(Ok(0x0002), vm.mem_read(MemoryMappedRegisters::MrPSR as u16));
+        assert_eq!(
+            Ok(()),
+            vm.mem_write(MemoryMappedRegisters::MrPSR as u16, 0x8304)
+        );
+        assert_eq!(vm.privilege(), Privilege::User);
+        assert_eq!(vm.priority(), 3);
+        assert_eq!(vm.registers[Register::COND], 4);
+        assert_eq!(vm.psr(), 0x8304);
+    }
+
+    #[test]
+    fn user_mode_access_to_privileged_memory_without_handler_throws_error() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.origin = 0x3000;
+        // LDI R0 with the address xFE00 stored right after the instruction.
+        let image_file = vec![0x30, 0x00, 0xA0, 0x00, 0xFE, 0x00];
+        assert_eq!(Ok(()), read_image_file(&mut vm, image_file));
+        vm.set_pc_with_origin();
+        vm.set_privilege(Privilege::User);
+        assert_eq!(Err(VMError::AccessControlViolation(0xFE00)), vm.step());
+    }
+
+    #[test]
+    fn user_mode_store_indirect_with_pointer_in_system_space_throws_error() {
+        for engine in [ExecutionEngine::Interpreter, ExecutionEngine::BasicBlock] {
+            // STI R0 with the pointer stored at x2FFF, right before the program.
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(TrapMode::Native)
+                .engine(engine)
+                .image(vec![0x30, 0x00, 0xB1, 0xFE, 0xF0, 0x25])
+                .build()
+                .unwrap();
+            vm.memory[0x2FFF] = 0x4000;
+            vm.registers[Register::R0] = 1;
+            assert_eq!(Err(VMError::AccessControlViolation(0x2FFF)), vm.run());
+            assert_eq!(vm.memory[0x4000], 0);
+        }
+    }
+
+    #[test]
+    fn access_control_violation_switches_to_supervisor_stack() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.origin = 0x3000;
+        // LDI R0 with the address xFE00 stored right after the instruction.
+        let image_file = vec![0x30, 0x00, 0xA0, 0x00, 0xFE, 0x00];
+        assert_eq!(Ok(()), read_image_file(&mut vm, image_file));
+        vm.memory[0x0102] = 0x1000; // ACV service routine
+        vm.set_pc_with_origin();
+        vm.set_privilege(Privilege::User);
+        vm.set_priority(1);
+        vm.registers[Register::COND] = 1;
+        vm.registers[Register::R6] = 0xF000;
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x1000);
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+        assert_eq!(vm.priority(), 1);
+        assert_eq!(vm.saved_usp, 0xF000);
+        assert_eq!(vm.registers[Register::R6], 0x2FFE);
+        assert_eq!(vm.memory[0x2FFF], 0x8101); // Saved PSR
+        assert_eq!(vm.memory[0x2FFE], 0x3001); // Saved PC
+    }
+
+    #[test]
+    fn return_from_interrupt_restores_user_mode() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.memory[0x0101] = 0x1000; // Illegal opcode service routine
+        vm.memory[0x1000] = 0x8000; // RTI
+        vm.memory[0x3000] = 0xD000; // Reserved opcode
+        vm.registers[Register::PC] = 0x3000;
+        vm.registers[Register::R6] = 0xF000;
+        vm.registers[Register::COND] = 4;
+        vm.set_privilege(Privilege::User);
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x1000);
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+        vm.registers[Register::COND] = 1;
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x3001);
+        assert_eq!(vm.privilege(), Privilege::User);
+        assert_eq!(vm.registers[Register::COND], 4);
+        assert_eq!(vm.registers[Register::R6], 0xF000);
+        assert_eq!(vm.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn reserved_opcode_without_handler_throws_error() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.memory[0] = 0xD000;
+        assert_eq!(
+            Err(VMError::InvalidInstruction(
+                HardwareError::InvalidInstruction(13)
+            )),
+            vm.step()
+        );
+    }
+
+    #[test]
+    fn return_from_interrupt_in_user_mode_raises_privilege_mode_violation() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.memory[0x3000] = 0x8000; // RTI
+        vm.registers[Register::PC] = 0x3000;
+        vm.set_privilege(Privilege::User);
+        assert_eq!(Err(VMError::PrivilegeModeViolation(0x3000)), vm.step());
+
+        vm.memory[0x0100] = 0x1000; // Privilege mode violation service routine
+        vm.registers[Register::PC] = 0x3000;
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x1000);
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+    }
+
+    #[test]
+    fn keyboard_interrupt_runs_service_routine() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R1, ENABLE
+            STI R1, KBSR
+    LOOP    BR LOOP
+    ENABLE  .FILL x4000
+    KBSR    .FILL xFE00
+            .END",
+        )
+        .unwrap();
+        let service_routine = assemble(
+            "
+            .ORIG x1000
+            LDI R0, KBDR
+            RTI
+    KBDR    .FILL xFE02
+            .END",
+        )
+        .unwrap();
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.set_console(Box::new(BufferConsole::new(b"a")));
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        assert_eq!(
+            Ok(()),
+            read_image_file(&mut vm, service_routine.obj_image())
+        );
+        vm.memory[0x0180] = 0x1000; // Keyboard interrupt vector
+        vm.set_pc_with_origin();
+        vm.registers[Register::R6] = 0x3000;
+
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(Ok(()), vm.step());
+        // The interrupt is serviced before executing the next instruction.
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x1001);
+        assert_eq!(vm.priority(), 4);
+        assert_eq!(vm.memory[0x2FFE], 0x3002); // Saved PC
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::R0], 'a' as u16);
+        assert_eq!(vm.registers[Register::PC], 0x3002);
+        assert_eq!(vm.priority(), 0);
+        for _ in 0..5 {
+            assert_eq!(Ok(()), vm.step());
+        }
+        assert_eq!(vm.registers[Register::PC], 0x3002);
+    }
+
+    #[test]
+    fn keyboard_interrupts_enabled_without_input_dont_stop_a_scripted_run() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R1, ENABLE
+            STI R1, KBSR
+            AND R2, R2, #0
+            ADD R2, R2, #3
+    LOOP    ADD R2, R2, #-1
+            BRp LOOP
+            LEA R0, MESSAGE
+            PUTS
+            HALT
+    ENABLE  .FILL x4000
+    KBSR    .FILL xFE00
+    MESSAGE .STRINGZ \"ok\"
+            .END",
+        )
+        .unwrap();
+        for trap_mode in [TrapMode::Native, TrapMode::MemoryResident] {
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(trap_mode)
+                .privilege(Privilege::Supervisor)
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            vm.set_console(Box::new(ScriptedConsole::new(b"", Vec::new())));
+            assert_eq!(Ok(()), vm.run(), "{:?}", trap_mode);
+        }
+    }
+
+    #[test]
+    fn raised_interrupt_waits_for_lower_priority() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.memory[0x0181] = 0x1000;
+        vm.registers[Register::PC] = 0x3000;
+        vm.set_priority(5);
+        vm.raise_interrupt(InterruptRequest {
+            vector: 0x81,
+            priority: 5,
+        });
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x3001);
+        vm.set_priority(2);
+        assert_eq!(Ok(()), vm.step());
+        assert_eq!(vm.registers[Register::PC], 0x1001);
+        assert_eq!(vm.priority(), 5);
+    }
+
     #[test]
     fn reding_empty_image_file_throws_eror() {
         let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
@@ -965,6 +1914,259 @@ mod tests {
         );
     }
 
+    #[test]
+    fn trap_routines_use_attached_console() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        let console = BufferConsole::new(b"k");
+        let output = console.output();
+        vm.set_console(Box::new(console));
+        vm.memory[0x100] = 'h' as u16;
+        vm.memory[0x101] = 'i' as u16;
+        vm.registers[Register::R0] = 0x100;
+        assert_eq!(Ok(()), vm.trap_puts());
+        assert_eq!(Ok(()), vm.trap_getc());
+        assert_eq!(vm.registers[Register::R0], 'k' as u16);
+        assert_eq!(Ok(()), vm.trap_out());
+        assert_eq!(*output.lock().unwrap(), b"hik");
+    }
+
+    #[test]
+    fn keyboard_status_register_polls_attached_console() {
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.set_console(Box::new(BufferConsole::new(b"w")));
+        assert_eq!(
+            Ok(1 << 15),
+            vm.mem_read(MemoryMappedRegisters::MrKBSR as u16)
+        );
+        assert_eq!(
+            Ok('w' as u16),
+            vm.mem_read(MemoryMappedRegisters::MrKBDR as u16)
+        );
+        assert_eq!(Ok(0), vm.mem_read(MemoryMappedRegisters::MrKBSR as u16));
+    }
+
+    #[test]
+    fn display_data_register_writes_to_console() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LEA R1, TEXT
+    NEXT    LDR R0, R1, #0
+            BRz DONE
+    WAIT    LDI R2, DSR
+            BRzp WAIT
+            STI R0, DDR
+            ADD R1, R1, #1
+            BRnzp NEXT
+    DONE    HALT
+    DSR     .FILL xFE04
+    DDR     .FILL xFE06
+    TEXT    .STRINGZ \"hi\"
+            .END",
+        )
+        .unwrap();
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.set_console(Box::new(console));
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        vm.set_pc_with_origin();
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(*output.lock().unwrap(), b"hi");
+        assert_eq!(vm.memory[MemoryMappedRegisters::MrDDR as usize], 'i' as u16);
+
+        // Only the interrupt enable bit of DSR can be written.
+        assert_eq!(
+            Ok(()),
+            vm.mem_write(MemoryMappedRegisters::MrDSR as u16, 0x4000)
+        );
+        assert_eq!(Ok(0xC000), vm.mem_read(MemoryMappedRegisters::MrDSR as u16));
+        assert_eq!(Ok(()), vm.mem_write(MemoryMappedRegisters::MrDSR as u16, 0));
+        assert_eq!(Ok(0x8000), vm.mem_read(MemoryMappedRegisters::MrDSR as u16));
+    }
+
+    #[test]
+    fn display_interrupt_runs_service_routine() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R1, ENABLE
+            STI R1, DSR
+    LOOP    BR LOOP
+    ENABLE  .FILL x4000
+    DSR     .FILL xFE04
+            .END",
+        )
+        .unwrap();
+        // Writes one character and disables the display interrupt.
+        let service_routine = assemble(
+            "
+            .ORIG x1000
+            LD R0, CHAR
+            STI R0, DDR
+            AND R0, R0, #0
+            STI R0, DSR
+            RTI
+    CHAR    .FILL x0021
+    DDR     .FILL xFE06
+    DSR     .FILL xFE04
+            .END",
+        )
+        .unwrap();
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        vm.set_console(Box::new(console));
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        assert_eq!(
+            Ok(()),
+            read_image_file(&mut vm, service_routine.obj_image())
+        );
+        vm.memory[0x0181] = 0x1000; // Display interrupt vector
+        vm.set_pc_with_origin();
+        vm.registers[Register::R6] = 0x3000;
+
+        for _ in 0..10 {
+            assert_eq!(Ok(()), vm.step());
+        }
+        assert_eq!(*output.lock().unwrap(), b"!");
+        assert_eq!(vm.registers[Register::PC], 0x3002);
+        assert_eq!(vm.priority(), 0);
+    }
+
+    #[test]
+    fn clearing_machine_control_register_stops_the_clock() {
+        let program = assemble(
+            "
+            .ORIG x3000
+    START   LDI R0, MCR
+            ST R0, SEEN
+            LD R1, MASK
+            AND R0, R0, R1
+            STI R0, MCR
+            ADD R2, R2, #1
+            BRnzp START
+    MCR     .FILL xFFFE
+    MASK    .FILL x7FFF
+    SEEN    .BLKW 1
+            .END",
+        )
+        .unwrap();
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        vm.set_pc_with_origin();
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(vm.memory[0x3009], 0x8000);
+        assert_eq!(vm.registers[Register::PC], 0x3005);
+        assert_eq!(vm.registers[Register::R2], 0);
+        assert_eq!(vm.mcr(), 0);
+
+        // Starting the clock again continues after the instruction that stopped it.
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(vm.registers[Register::R2], 1);
+        assert_eq!(vm.registers[Register::PC], 0x3005);
+    }
+
+    #[test]
+    fn timer_interrupt_preempts_the_program() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R1, INTERVAL
+            STI R1, TIR
+            LD R1, ENABLE
+            STI R1, TSR
+    LOOP    ADD R2, R2, #1
+            BRnzp LOOP
+    INTERVAL .FILL #10
+    ENABLE  .FILL x4000
+    TIR     .FILL xFE0A
+    TSR     .FILL xFE08
+            .END",
+        )
+        .unwrap();
+        // Counts the interrupts in R3 and acknowledges the timer reading its status.
+        let service_routine = assemble(
+            "
+            .ORIG x1000
+            ADD R3, R3, #1
+            LDI R4, TSR
+            RTI
+    TSR     .FILL xFE08
+            .END",
+        )
+        .unwrap();
+        let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+        assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+        assert_eq!(
+            Ok(()),
+            read_image_file(&mut vm, service_routine.obj_image())
+        );
+        vm.memory[0x0182] = 0x1000; // Timer interrupt vector
+        vm.set_pc_with_origin();
+        vm.registers[Register::R6] = 0x3000;
+
+        for _ in 0..40 {
+            assert_eq!(Ok(()), vm.step());
+        }
+        assert_eq!(vm.registers[Register::R3], 3);
+        assert_eq!(vm.registers[Register::R4], 0xC000);
+        assert_eq!(Ok(10), vm.mem_read(MemoryMappedRegisters::MrTIR as u16));
+    }
+
+    #[test]
+    fn replayed_input_reproduces_the_recorded_run() {
+        // Counts in R1 the polls until a key is pressed, and then prints the key.
+        let program = assemble(
+            "
+            .ORIG x3000
+    POLL    ADD R1, R1, #1
+            LDI R0, KBSR
+            BRzp POLL
+            LDI R0, KBDR
+            OUT
+            GETC
+            OUT
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END",
+        )
+        .unwrap();
+        let run = |log: Option<InputLog>| {
+            let console = BufferConsole::new(b"xy");
+            let output = console.output();
+            let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
+            vm.set_trap_mode(TrapMode::Native);
+            vm.set_console(Box::new(console));
+            assert_eq!(Ok(()), read_image_file(&mut vm, program.obj_image()));
+            vm.set_pc_with_origin();
+            vm.start_recording();
+            if let Some(log) = log {
+                vm.replay_input(log);
+            }
+            assert_eq!(Ok(()), vm.run());
+            let output = output.lock().unwrap().clone();
+            (
+                vm.registers[Register::R1],
+                output,
+                vm.take_recording().unwrap(),
+            )
+        };
+
+        let (polls, output, recording) = run(None);
+        assert_eq!((polls, output.as_slice()), (1, b"xy".as_slice()));
+        assert_eq!(recording.events.len(), 2);
+        assert_eq!(recording.events[0].instruction, 2);
+
+        let mut log = InputLog::new();
+        log.record(8, b'a');
+        log.record(12, b'b');
+        let (polls, output, recording) = run(Some(log.clone()));
+        assert_eq!((polls, output.as_slice()), (3, b"ab".as_slice()));
+        assert_eq!(recording, log);
+    }
+
     #[test]
     fn executin_invalid_trap_code_throws_error() {
         let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
@@ -979,4 +2181,81 @@ mod tests {
             vm.run()
         );
     }
+
+    #[test]
+    fn instruction_limit_stops_an_infinite_loop() {
+        let program = assemble(".ORIG x3000\nADD R1, R1, #1\nLOOP BRnzp LOOP\n.END").unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .instruction_limit(100)
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(VMError::InstructionLimitExceeded(0x3001, 100))
+        );
+        assert_eq!(vm.registers[Register::R1], 1);
+        // The limit counts the instructions already executed, so running again stops right away.
+        assert_eq!(
+            vm.run(),
+            Err(VMError::InstructionLimitExceeded(0x3001, 100))
+        );
+        vm.set_instruction_limit(Some(150));
+        assert_eq!(
+            vm.run(),
+            Err(VMError::InstructionLimitExceeded(0x3001, 150))
+        );
+    }
+
+    #[test]
+    fn timeout_stops_an_infinite_loop() {
+        let program = assemble(".ORIG x3000\nLOOP BRnzp LOOP\n.END").unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .timeout(Duration::from_millis(20))
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        let result = vm.run();
+        assert!(
+            matches!(result, Err(VMError::Timeout(0x3000, count)) if count == vm.instruction_count),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn self_modifying_code_runs_the_new_instruction() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            AND R1, R1, #0
+            AND R2, R2, #0
+            ADD R2, R2, #2
+    LOOP    ADD R1, R1, #1
+            LD R3, PATCH
+            ST R3, LOOP
+            ADD R2, R2, #-1
+            BRp LOOP
+            HALT
+    PATCH   ADD R1, R1, #5
+            .END",
+        )
+        .unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        assert_eq!(Ok(()), vm.run());
+        // The first pass adds 1 and the second one runs the patched instruction, which was cached unpatched.
+        assert_eq!(vm.registers[Register::R1], 6);
+
+        // Writing memory directly, without the vm, also replaces the cached instruction.
+        vm.memory[0x3003] = 0x127F; // ADD R1, R1, #-1
+        vm.registers[Register::PC] = 0x3003;
+        assert_eq!(Ok(()), vm.run());
+        assert_eq!(vm.registers[Register::R1], 5);
+    }
 }
@@ -0,0 +1,60 @@
+//! LC3 virtual machine library.
+//!
+//! The crate exposes the [`LC3VirtualMachine`] together with its hardware components so the vm can be embedded in
+//! other programs. The easiest way to get a machine ready to run is through [`LC3VirtualMachineBuilder`]:
+//!
+//! ```no_run
+//! use lc3_vm::LC3VirtualMachine;
+//!
+//! let mut vm = LC3VirtualMachine::builder()
+//!     .image_path("example_images/2048.obj")
+//!     .build()?;
+//! vm.run()?;
+//! # Ok::<(), lc3_vm::VMError>(())
+//! ```
+pub mod assembler;
+pub mod block_engine;
+pub mod builder;
+pub mod call_stack;
+pub mod console;
+pub mod coverage;
+pub mod debugger;
+pub mod decode_cache;
+pub mod disassembler;
+pub mod gdb;
+pub mod grader;
+pub mod hardware;
+pub mod interrupts;
+pub mod lc3_vm;
+pub mod os;
+pub mod profiler;
+pub mod replay;
+pub mod snapshot;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+
+pub use assembler::{AssembledProgram, AssemblerError, assemble};
+pub use builder::LC3VirtualMachineBuilder;
+pub use call_stack::{Backtrace, BacktraceFrame, CallFrame, CallKind, CallStack};
+pub use console::{BufferConsole, ConsoleDevice, PipeConsole, ScriptedConsole, StdioConsole};
+pub use coverage::{BranchCoverage, Coverage, CoverageReport, LineCoverage};
+pub use debugger::{Debugger, DebuggerCommand, StopReason};
+pub use disassembler::{
+    DisassembledWord, disassemble_image, disassemble_instruction, disassemble_memory,
+};
+pub use gdb::{GdbStub, serve_gdb};
+pub use grader::{CaseResult, GradingOptions, TestCase, TestSuite, run_test_case};
+pub use hardware::{DecodedInstruction, ExecutionEngine, Privilege, Register, TrapMode};
+pub use interrupts::{InterruptController, InterruptRequest};
+pub use lc3_vm::{
+    LC3VirtualMachine, VMError, disable_input_buffering, read_image, read_image_file,
+    restore_input_buffering,
+};
+pub use os::{OS_SOURCE, os_image, os_symbols};
+pub use profiler::{HotSpot, Profiler};
+pub use replay::{InputEvent, InputLog, InputReplay};
+pub use snapshot::{load_snapshot, load_snapshot_file, save_snapshot, save_snapshot_file};
+pub use symbols::{DebugInfo, SymbolTable};
+pub use timer::Timer;
+pub use trace::{CondChange, MemoryAccess, TraceFormat, TraceRecord, Tracer};
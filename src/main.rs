@@ -1,31 +1,543 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use lc3_vm::{
-    LC3VirtualMachine, VMError, disable_input_buffering, read_image, restore_input_buffering,
+    Backtrace, Coverage, DebugInfo, Debugger, ExecutionEngine, GradingOptions, InputLog,
+    LC3VirtualMachine, Privilege, Profiler, ScriptedConsole, SymbolTable, TestSuite, TraceFormat,
+    Tracer, TrapMode, VMError, assemble, disable_input_buffering, disassemble_image,
+    load_snapshot_file, os_symbols, restore_input_buffering, save_snapshot_file, serve_gdb,
 };
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 use termios::Termios;
-pub mod hardware;
-mod lc3_vm;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
     /// Path of the image to run on the vm
-    #[arg(short, long)]
-    path: String,
+    #[arg(short, long, required_unless_present = "load_state")]
+    path: Option<String>,
+
+    /// Runs without a terminal, reading the input from --input or --input-file and writing the output to
+    /// --output-file or stdout. Exits with status 0 if the program halts, 2 if it needs more input than it was given,
+    /// 3 if it exceeds --max-instructions or --timeout and 1 if it fails
+    #[arg(long)]
+    headless: bool,
+
+    /// Input for the program in headless mode
+    #[arg(long, requires = "headless", conflicts_with = "input_file")]
+    input: Option<String>,
+
+    /// Path of a file with the input for the program in headless mode
+    #[arg(long, requires = "headless")]
+    input_file: Option<String>,
+
+    /// Path of the file where the program output is written in headless mode
+    #[arg(long, requires = "headless")]
+    output_file: Option<String>,
+
+    #[command(flatten)]
+    vm_options: VmOptions,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Options to configure the vm, shared by the commands that run an image.
+#[derive(clap::Args, Debug)]
+struct VmOptions {
+    /// Runs the trap routines natively in the vm instead of loading an OS image
+    #[arg(long, global = true)]
+    native_traps: bool,
+
+    /// Path of an OS image with the trap routines to load instead of the default one
+    #[arg(long, global = true, conflicts_with = "native_traps")]
+    os: Option<String>,
+
+    /// Runs the program in supervisor mode, so it can access the device registers and system space. TRAP then saves
+    /// the PSR and the PC on the stack of the program instead of switching to the supervisor stack
+    #[arg(long, global = true)]
+    supervisor: bool,
+
+    /// Path of a snapshot to restore after loading the image
+    #[arg(long, global = true)]
+    load_state: Option<String>,
+
+    /// Path where a snapshot of the vm is saved when it stops running
+    #[arg(long, global = true)]
+    save_state: Option<String>,
+
+    /// Path where the input consumed by the program is logged when it stops running
+    #[arg(long, global = true)]
+    record_input: Option<String>,
+
+    /// Path of an input log to feed to the program, at the same instruction counts it was recorded
+    #[arg(long, global = true)]
+    replay_input: Option<String>,
+
+    /// Stops the program with an error once it executed this number of instructions
+    #[arg(long, global = true)]
+    max_instructions: Option<u64>,
+
+    /// Stops the program with an error once it ran for this number of seconds.
+    /// The time is only checked every 1024 instructions
+    #[arg(long, global = true, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Path where a trace of every instruction executed is written
+    #[arg(long, global = true)]
+    trace: Option<String>,
+
+    /// Format of the trace: JSON Lines or a compact binary format
+    #[arg(long, global = true, requires = "trace", value_parser = ["json", "binary"], default_value = "json")]
+    trace_format: String,
+
+    /// Range of addresses like x3000:x30FF whose instructions are traced, can be repeated.
+    /// Every instruction is traced by default
+    #[arg(long, global = true, requires = "trace", value_parser = parse_range)]
+    trace_range: Vec<RangeInclusive<u16>>,
+
+    /// Engine that executes the program: one instruction at a time or compiled basic blocks
+    #[arg(long, global = true, value_parser = ["interpreter", "block"], default_value = "interpreter")]
+    engine: String,
+
+    /// Counts the instructions executed per address and per opcode and prints the most executed ones when the program
+    /// stops
+    #[arg(long, global = true)]
+    profile: bool,
+
+    /// Path of a .sym file with the labels of the image, can be repeated. By default the .sym file next to the image
+    /// is used if there's any
+    #[arg(long, global = true)]
+    symbols: Vec<String>,
+
+    /// Path where an lcov report of the source lines and branches executed is written when the program stops. With
+    /// the test command it covers every case of the suite
+    #[arg(long, global = true)]
+    coverage: Option<String>,
+
+    /// Path of the .dbg file that maps the image to its source lines. By default the .dbg file next to the image is
+    /// used
+    #[arg(long, global = true)]
+    debug_info: Option<String>,
 }
 
-fn main() -> Result<(), VMError> {
+/// Number of addresses and labels listed in the profile report.
+const PROFILE_TOP: usize = 20;
+
+impl VmOptions {
+    /// Builds the vm loading the image at path, if there's any, and then the snapshot to restore.
+    fn build_vm(&self, path: Option<&str>) -> Result<LC3VirtualMachine, Box<dyn Error>> {
+        let mut builder = LC3VirtualMachine::builder();
+        if self.native_traps {
+            builder = builder.trap_mode(TrapMode::Native);
+        }
+        if let Some(os_path) = &self.os {
+            builder = builder.os_image(fs::read(os_path)?);
+        }
+        if self.supervisor {
+            builder = builder.privilege(Privilege::Supervisor);
+        }
+        if let Some(path) = path {
+            builder = builder.image_path(path);
+        }
+        if let Some(limit) = self.max_instructions {
+            builder = builder.instruction_limit(limit);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let mut vm = builder.engine(self.engine()).build()?;
+        vm.set_symbols(self.symbol_table(path)?);
+        if let Some(state_path) = &self.load_state {
+            load_snapshot_file(&mut vm, state_path)?;
+        }
+        if let Some(log_path) = &self.replay_input {
+            vm.replay_input(InputLog::load(log_path)?);
+        }
+        if self.record_input.is_some() {
+            vm.start_recording();
+        }
+        if let Some(trace_path) = &self.trace {
+            let format = match self.trace_format.as_str() {
+                "binary" => TraceFormat::Binary,
+                _ => TraceFormat::JsonLines,
+            };
+            let mut tracer = Tracer::new(BufWriter::new(File::create(trace_path)?), format);
+            for range in &self.trace_range {
+                tracer = tracer.with_range(range.clone());
+            }
+            vm.set_tracer(Some(tracer));
+        }
+        if self.profile {
+            vm.set_profiler(Some(Profiler::new()));
+        }
+        if self.coverage.is_some() {
+            self.debug_info_path(path)?;
+            vm.set_coverage(Some(Coverage::new()));
+        }
+        Ok(vm)
+    }
+
+    fn engine(&self) -> ExecutionEngine {
+        match self.engine.as_str() {
+            "block" => ExecutionEngine::BasicBlock,
+            _ => ExecutionEngine::Interpreter,
+        }
+    }
+
+    /// Options for the vms that run the cases of a test suite, which fails for the options that only make sense for
+    /// a single run.
+    fn grading_options(&self) -> Result<GradingOptions, Box<dyn Error>> {
+        let unsupported = [
+            ("--load-state", self.load_state.is_some()),
+            ("--save-state", self.save_state.is_some()),
+            ("--record-input", self.record_input.is_some()),
+            ("--replay-input", self.replay_input.is_some()),
+            ("--trace", self.trace.is_some()),
+            ("--profile", self.profile),
+            ("--symbols", !self.symbols.is_empty()),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, given)| *given) {
+            return Err(format!("The test command doesn't support {}", flag).into());
+        }
+        let mut grading_options = GradingOptions::new();
+        grading_options.instruction_limit = self.max_instructions;
+        grading_options.timeout = self.timeout;
+        if self.supervisor {
+            grading_options.privilege = Privilege::Supervisor;
+        }
+        grading_options.engine = self.engine();
+        if self.native_traps {
+            grading_options.trap_mode = TrapMode::Native;
+        }
+        if let Some(os_path) = &self.os {
+            grading_options.os_image = Some(fs::read(os_path)?);
+        }
+        Ok(grading_options)
+    }
+
+    /// Path of the line table of the image at path, which coverage reports need.
+    fn debug_info_path(&self, path: Option<&str>) -> Result<String, Box<dyn Error>> {
+        if let Some(debug_info_path) = &self.debug_info {
+            return Ok(debug_info_path.clone());
+        }
+        path.map(|path| Path::new(path).with_extension("dbg"))
+            .filter(|debug_info_path| debug_info_path.exists())
+            .map(|debug_info_path| debug_info_path.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                "Coverage needs the .dbg file written by the assemble command, pass it with --debug-info".into()
+            })
+    }
+
+    /// Writes the lcov report of coverage for the image at path, whose program is in memory.
+    fn write_coverage(
+        &self,
+        coverage: &Coverage,
+        path: Option<&str>,
+        memory: &[u16],
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(coverage_path) = &self.coverage {
+            let debug_info = DebugInfo::load(&self.debug_info_path(path)?)?;
+            let report = coverage.report(&debug_info, memory);
+            fs::write(coverage_path, report.lcov())?;
+            eprintln!("{}", report);
+        }
+        Ok(())
+    }
+
+    /// Loads the labels of the image at path and of the default OS, if it's loaded.
+    fn symbol_table(&self, path: Option<&str>) -> Result<SymbolTable, Box<dyn Error>> {
+        let mut symbols = SymbolTable::new();
+        if !self.native_traps && self.os.is_none() {
+            symbols.extend(os_symbols());
+        }
+        let default_path = path
+            .map(|path| Path::new(path).with_extension("sym"))
+            .filter(|sym_path| sym_path.exists());
+        if self.symbols.is_empty()
+            && let Some(sym_path) = default_path
+        {
+            symbols.extend(SymbolTable::load(&sym_path.to_string_lossy())?.symbols());
+        }
+        for sym_path in &self.symbols {
+            symbols.extend(SymbolTable::load(sym_path)?.symbols());
+        }
+        Ok(symbols)
+    }
+
+    /// Saves the snapshot of the vm, the recorded input and the reports of the image at path, if they were asked for.
+    fn finish(&self, vm: &mut LC3VirtualMachine, path: Option<&str>) -> Result<(), Box<dyn Error>> {
+        if let Some(state_path) = &self.save_state {
+            save_snapshot_file(vm, state_path)?;
+        }
+        if let (Some(log_path), Some(recording)) = (&self.record_input, vm.take_recording()) {
+            recording.save(log_path)?;
+        }
+        if let Some(mut tracer) = vm.take_tracer() {
+            tracer.flush()?;
+        }
+        if let Some(profiler) = vm.take_profiler() {
+            eprint!("{}", profiler.report(vm.symbols(), PROFILE_TOP));
+        }
+        if let Some(coverage) = vm.take_coverage() {
+            self.write_coverage(&coverage, path, &vm.memory)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Assembles an LC3 assembly source file into an object image, a symbol table and a line table
+    Assemble {
+        /// Path of the assembly source file
+        source: String,
+        /// Path of the object image to write, by default the source path with .obj extension.
+        /// The symbol table and the line table are written next to it with .sym and .dbg extensions
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Prints the content of an object image as LC3 assembly
+    Disasm {
+        /// Path of the object image
+        image: String,
+    },
+    /// Runs an image under the interactive debugger
+    Debug {
+        /// Path of the image to debug
+        image: String,
+        /// Address of a breakpoint to set before starting, can be repeated
+        #[arg(short, long, value_parser = parse_address)]
+        breakpoint: Vec<u16>,
+        /// Input for the program, queued before starting. More can be queued with the input command of the debugger
+        #[arg(long)]
+        input: Option<String>,
+    },
+    /// Runs an image waiting for a GDB remote protocol client on a local TCP port
+    Gdb {
+        /// Path of the image to debug
+        image: String,
+        /// Local TCP port to listen on
+        #[arg(long, default_value_t = 1234)]
+        port: u16,
+    },
+    /// Grades an image with a test suite, reporting which cases pass. Exits with status 1 if any case fails.
+    /// --max-instructions and --timeout apply to every case, unless the case or the suite set their own instruction
+    /// limit. The registers are checked as the program left them when it called HALT, even when the trap routines
+    /// run from memory. Saving or restoring the state, recording or replaying the input, tracing and profiling aren't
+    /// supported
+    Test {
+        /// Path of the image to grade
+        image: String,
+        /// Path of the test suite, in JSON if it has .json extension and in TOML otherwise
+        suite: String,
+    },
+}
+
+fn parse_address(address: &str) -> Result<u16, String> {
+    let hex = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix('x'))
+        .ok_or(format!(
+            "{} is not an hexadecimal address like x3000",
+            address
+        ))?;
+    u16::from_str_radix(hex, 16).map_err(|error| error.to_string())
+}
+
+fn parse_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = range.split_once(':').ok_or(format!(
+        "{} is not an address range like x3000:x30FF",
+        range
+    ))?;
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(seconds.parse::<f64>().map_err(|error| error.to_string())?)
+        .map_err(|error| error.to_string())
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     let args = Args::parse();
+    let options = &args.vm_options;
+
+    match args.command {
+        Some(Command::Assemble { source, output }) => assemble_file(&source, output),
+        Some(Command::Disasm { image }) => disassemble_file(&image),
+        Some(Command::Debug {
+            image,
+            breakpoint,
+            input,
+        }) => debug_image(options, &image, &breakpoint, input.as_deref().unwrap_or("")),
+        Some(Command::Gdb { image, port }) => gdb_server(options, &image, port),
+        Some(Command::Test { image, suite }) => grade_image(options, &image, &suite),
+        None if args.headless => {
+            let input = match (&args.input, &args.input_file) {
+                (Some(input), _) => input.as_bytes().to_vec(),
+                (None, Some(input_path)) => fs::read(input_path)?,
+                (None, None) => Vec::new(),
+            };
+            run_headless(
+                options,
+                args.path.as_deref(),
+                &input,
+                args.output_file.as_deref(),
+            )
+        }
+        None => run_image(options, args.path.as_deref()),
+    }
+}
+
+fn run_image(options: &VmOptions, path: Option<&str>) -> Result<ExitCode, Box<dyn Error>> {
+    let mut vm = options.build_vm(path)?;
 
     let mut term = Termios::from_fd(0).unwrap();
     disable_input_buffering(&mut term)?;
+    let result = vm.run();
+    restore_input_buffering(&mut term)?;
 
-    let mut vm: LC3VirtualMachine = LC3VirtualMachine::new();
-    vm.set_pc_with_origin();
-    vm.turn_pos_flag_on();
-    read_image(&mut vm, &args.path)?;
-    vm.run()?;
+    options.finish(&mut vm, path)?;
+    if let Err(error) = result {
+        eprintln!("Error: {}\n{}", error, vm.backtrace());
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    restore_input_buffering(&mut term)?;
-    Ok(())
+/// Runs the image without touching the terminal, with the input given in advance.
+fn run_headless(
+    options: &VmOptions,
+    path: Option<&str>,
+    input: &[u8],
+    output_path: Option<&str>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut vm = options.build_vm(path)?;
+    let output: Box<dyn Write + Send> = match output_path {
+        Some(output_path) => Box::new(File::create(output_path)?),
+        None => Box::new(io::stdout()),
+    };
+    vm.set_console(Box::new(ScriptedConsole::new(input, output)));
+    let result = vm.run();
+    vm.console_mut().flush()?;
+    options.finish(&mut vm, path)?;
+    Ok(headless_exit_code(result, &vm.backtrace()))
+}
+
+/// Exit status of a headless run, derived from how the program stopped. Errors are printed with the backtrace of
+/// where the program stopped.
+fn headless_exit_code(result: Result<(), VMError>, backtrace: &Backtrace) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(VMError::EndOfInput) => {
+            eprintln!(
+                "The program needs more input than it was given\n{}",
+                backtrace
+            );
+            ExitCode::from(2)
+        }
+        Err(error @ (VMError::InstructionLimitExceeded(_, _) | VMError::Timeout(_, _))) => {
+            eprintln!("{}\n{}", error, backtrace);
+            ExitCode::from(3)
+        }
+        Err(error) => {
+            eprintln!("{}\n{}", error, backtrace);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn assemble_file(source_path: &str, output: Option<String>) -> Result<ExitCode, Box<dyn Error>> {
+    let source = fs::read_to_string(source_path)?;
+    let program = assemble(&source).map_err(|error| format!("{}: {}", source_path, error))?;
+    let obj_path = match output {
+        Some(output) => Path::new(&output).to_path_buf(),
+        None => Path::new(source_path).with_extension("obj"),
+    };
+    fs::write(&obj_path, program.obj_image())?;
+    fs::write(obj_path.with_extension("sym"), program.sym_file())?;
+    fs::write(
+        obj_path.with_extension("dbg"),
+        program.debug_file(source_path),
+    )?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn disassemble_file(image_path: &str) -> Result<ExitCode, Box<dyn Error>> {
+    let image = fs::read(image_path)?;
+    for line in disassemble_image(&image)? {
+        println!("x{:04X}  x{:04X}  {}", line.address, line.word, line.text);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs the image under the debugger, which reads its commands from stdin. The program reads its input from a queue
+/// filled with the input command instead, so both don't fight for stdin.
+fn debug_image(
+    options: &VmOptions,
+    path: &str,
+    breakpoints: &[u16],
+    input: &str,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut vm = options.build_vm(Some(path))?;
+    let console = ScriptedConsole::new(input.as_bytes(), io::stdout());
+    let program_input = console.input();
+    vm.set_console(Box::new(console));
+    let mut debugger = Debugger::new(&mut vm);
+    debugger.set_input(program_input);
+    for address in breakpoints {
+        debugger.add_breakpoint(*address);
+    }
+    debugger.repl(io::stdin().lock(), &mut io::stdout())?;
+    options.finish(&mut vm, Some(path))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn gdb_server(options: &VmOptions, path: &str, port: u16) -> Result<ExitCode, Box<dyn Error>> {
+    let mut vm = options.build_vm(Some(path))?;
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for a GDB client on 127.0.0.1:{}", port);
+    serve_gdb(&mut vm, &listener)?;
+    options.finish(&mut vm, Some(path))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn grade_image(
+    options: &VmOptions,
+    image_path: &str,
+    suite_path: &str,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let image = fs::read(image_path)?;
+    let suite = TestSuite::load(suite_path)?;
+    let grading_options = options.grading_options()?;
+    let mut coverage = Coverage::new();
+    let results = match options.coverage {
+        Some(_) => {
+            options.debug_info_path(Some(image_path))?;
+            suite.run_with_coverage(&image, &grading_options, &mut coverage)
+        }
+        None => suite.run(&image, &grading_options),
+    };
+    for result in &results {
+        println!("{}", result);
+    }
+    let passed = results.iter().filter(|result| result.passed()).count();
+    println!("{} of {} cases passed", passed, results.len());
+    if options.coverage.is_some() {
+        let program = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .image(image)
+            .build()?;
+        options.write_coverage(&coverage, Some(image_path), &program.memory)?;
+    }
+    if passed == results.len() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
 }
@@ -0,0 +1,134 @@
+use crate::assembler::assemble;
+
+/// Source of the operating system loaded by default, which implements the trap routines in LC3 code.
+pub const OS_SOURCE: &str = include_str!("../os/lc3os.asm");
+
+/// Assembles the default operating system into an object image that loads at address 0x0000.
+pub fn os_image() -> Vec<u8> {
+    assemble(OS_SOURCE)
+        .expect("The default OS source is valid assembly")
+        .obj_image()
+}
+
+/// Labels of the default operating system with their addresses.
+pub fn os_symbols() -> Vec<(String, u16)> {
+    assemble(OS_SOURCE)
+        .expect("The default OS source is valid assembly")
+        .symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::hardware::{Privilege, Register, TrapMode};
+    use crate::lc3_vm::LC3VirtualMachine;
+
+    /// Runs a program with the default OS in privilege mode and returns the vm and everything it wrote to the
+    /// console.
+    fn run_in_mode(
+        source: &str,
+        input: &[u8],
+        privilege: Privilege,
+    ) -> (LC3VirtualMachine, Vec<u8>) {
+        let console = BufferConsole::new(input);
+        let output = console.output();
+        let mut vm = LC3VirtualMachine::builder()
+            .console(console)
+            .privilege(privilege)
+            .image(assemble(source).unwrap().obj_image())
+            .build()
+            .unwrap();
+        assert_eq!(vm.trap_mode(), TrapMode::MemoryResident);
+        assert_eq!(Ok(()), vm.run());
+        let output = output.lock().unwrap().clone();
+        (vm, output)
+    }
+
+    /// Runs a program with the default OS in supervisor and in user mode, checking that the routines behave the
+    /// same, and returns the vm that ran in user mode and everything it wrote to the console.
+    fn run_with_os(source: &str, input: &[u8]) -> (LC3VirtualMachine, Vec<u8>) {
+        let (supervisor_vm, supervisor_output) = run_in_mode(source, input, Privilege::Supervisor);
+        let (vm, output) = run_in_mode(source, input, Privilege::User);
+        assert_eq!(output, supervisor_output);
+        assert_eq!(vm.registers[..6], supervisor_vm.registers[..6]);
+        assert_eq!(
+            vm.registers[Register::R7],
+            supervisor_vm.registers[Register::R7]
+        );
+        // HALT stops the vm inside the routine, which runs in supervisor mode with the PSR and the return address
+        // of the program on the supervisor stack.
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+        assert_eq!(vm.registers[Register::R6], 0x2FFE);
+        assert_eq!(vm.memory[0x2FFF] & 0x8000, 0x8000);
+        (vm, output)
+    }
+
+    #[test]
+    fn os_fills_trap_vector_table() {
+        let mut vm = LC3VirtualMachine::new();
+        crate::lc3_vm::read_image_file(&mut vm, os_image()).unwrap();
+        for trap_vector in 0x20..=0x25 {
+            assert!(vm.memory[trap_vector] >= 0x0200);
+        }
+        assert_eq!(vm.memory[0x26], 0);
+        assert_eq!(vm.memory[0x0180], 0);
+    }
+
+    #[test]
+    fn getc_out_and_puts_run_in_memory() {
+        let source = "
+            .ORIG x3000
+            AND R1, R1, #0
+            ADD R1, R1, #9
+            GETC
+            OUT
+            LEA R0, TEXT
+            PUTS
+            ST R1, COPY
+            HALT
+    COPY    .BLKW 1
+    TEXT    .STRINGZ \"!\\n\"
+            .END";
+        let (vm, output) = run_with_os(source, b"k");
+        assert_eq!(output, b"k!\n");
+        // Routines preserve the registers they don't return values in.
+        assert_eq!(vm.memory[0x3008], 9);
+        assert_eq!(vm.registers[Register::R1], 9);
+        // HALT only changes R7, which held the return address also saved on the stack.
+        assert_eq!(vm.memory[0x2FFE], 0x3008);
+    }
+
+    #[test]
+    fn putsp_writes_two_characters_per_word() {
+        let source = "
+            .ORIG x3000
+            LEA R0, EVEN
+            PUTSP
+            LEA R0, ODD
+            PUTSP
+            HALT
+    EVEN    .FILL x6968 ; hi
+            .FILL x0000
+    ODD     .FILL x6F79 ; yo
+            .FILL x0021 ; !
+            .END";
+        let (_, output) = run_with_os(source, b"");
+        assert_eq!(output, b"hiyo!");
+    }
+
+    #[test]
+    fn in_prompts_and_echoes_the_character() {
+        let source = "
+            .ORIG x3000
+            IN
+            BRnz DONE           ; The condition codes are set by the character read
+            ST R0, CHAR
+    DONE    HALT
+    CHAR    .BLKW 1
+            .END";
+        let (vm, output) = run_with_os(source, b"q");
+        assert_eq!(output, b"Enter a character: \nq");
+        assert_eq!(vm.memory[0x3004], 'q' as u16);
+    }
+}
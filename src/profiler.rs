@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::disassembler::disassemble_instruction;
+use crate::symbols::SymbolTable;
+
+/// Mnemonics of the opcodes, indexed by opcode.
+const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP", "RES",
+    "LEA", "TRAP",
+];
+
+/// Width of the longest bar of the opcode histogram.
+const HISTOGRAM_WIDTH: u64 = 40;
+
+/// Address executed during a profiled run, with how many times it ran.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HotSpot {
+    pub address: u16,
+    /// Last word executed at the address.
+    pub word: u16,
+    pub count: u64,
+}
+
+/// Counts how many times every address and every opcode is executed.
+///
+/// Instructions are counted when they are fetched, so an instruction that fails or starts an exception still
+/// counts, while the cycles spent starting an interrupt service routine don't.
+pub struct Profiler {
+    counts: Vec<u64>,
+    words: Vec<u16>,
+    opcodes: [u64; 16],
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 1 << 16],
+            words: vec![0; 1 << 16],
+            opcodes: [0; 16],
+            total: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record(&mut self, pc: u16, word: u16) {
+        self.counts[pc as usize] += 1;
+        self.words[pc as usize] = word;
+        self.opcodes[(word >> 12) as usize] += 1;
+        self.total += 1;
+    }
+
+    /// Number of instructions executed while profiling.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Number of times the instruction at address was executed.
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    /// Number of executed instructions with each opcode, by mnemonic, skipping the opcodes that never ran.
+    pub fn opcode_counts(&self) -> Vec<(&'static str, u64)> {
+        OPCODE_NAMES
+            .iter()
+            .zip(self.opcodes)
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, count)| (*name, count))
+            .collect()
+    }
+
+    /// Every executed address, the most executed first and lower addresses first on ties.
+    pub fn hot_spots(&self) -> Vec<HotSpot> {
+        let mut hot_spots: Vec<HotSpot> = (0..=u16::MAX)
+            .filter(|address| self.counts[*address as usize] > 0)
+            .map(|address| HotSpot {
+                address,
+                word: self.words[address as usize],
+                count: self.counts[address as usize],
+            })
+            .collect();
+        hot_spots.sort_by(|a, b| b.count.cmp(&a.count).then(a.address.cmp(&b.address)));
+        hot_spots
+    }
+
+    /// Executed instructions attributed to the closest label before them, the most executed label first and in
+    /// alphabetical order on ties. Instructions with no label before them are attributed to their own address.
+    pub fn label_counts(&self, symbols: &SymbolTable) -> Vec<(String, u64)> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        for address in (0..=u16::MAX).filter(|address| self.counts[*address as usize] > 0) {
+            let label = match symbols.locate(address) {
+                Some((label, _)) => label.to_string(),
+                None => format!("x{:04X}", address),
+            };
+            *counts.entry(label).or_default() += self.counts[address as usize];
+        }
+        let mut labels: Vec<(String, u64)> = counts.into_iter().collect();
+        labels.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        labels
+    }
+
+    fn percentage(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+
+    /// Report with the top most executed addresses, the executed instructions per label if there are symbols and
+    /// a histogram of the opcodes.
+    pub fn report(&self, symbols: &SymbolTable, top: usize) -> String {
+        let mut report = format!("Profile of {} instructions\n", self.total);
+        let _ = writeln!(report, "\nHot spots:");
+        let _ = writeln!(
+            report,
+            "{:>12} {:>7}  {:<7} {:<20} instruction",
+            "count", "%", "address", "label"
+        );
+        for hot_spot in self.hot_spots().iter().take(top) {
+            let _ = writeln!(
+                report,
+                "{:>12} {:>6.2}%  x{:04X}   {:<20} {}",
+                hot_spot.count,
+                self.percentage(hot_spot.count),
+                hot_spot.address,
+                symbols.describe(hot_spot.address),
+                disassemble_instruction(hot_spot.address, hot_spot.word)
+            );
+        }
+        if !symbols.is_empty() {
+            let _ = writeln!(report, "\nBy label:");
+            for (label, count) in self.label_counts(symbols).iter().take(top) {
+                let _ = writeln!(
+                    report,
+                    "{:>12} {:>6.2}%  {}",
+                    count,
+                    self.percentage(*count),
+                    label
+                );
+            }
+        }
+        let _ = writeln!(report, "\nOpcodes:");
+        let most_executed = self.opcodes.iter().max().copied().unwrap_or(0).max(1);
+        for (name, count) in self.opcode_counts() {
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(most_executed) as usize);
+            let _ = writeln!(
+                report,
+                "{:<5} {:>12} {:>6.2}%  {}",
+                name,
+                count,
+                self.percentage(count),
+                bar
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::hardware::{ExecutionEngine, TrapMode};
+    use crate::lc3_vm::LC3VirtualMachine;
+
+    const COUNTDOWN: &str = "
+            .ORIG x3000
+            AND R0, R0, #0
+            ADD R0, R0, #5
+    LOOP    ADD R0, R0, #-1
+            BRp LOOP
+            HALT
+            .END";
+
+    fn profiled_run(engine: ExecutionEngine) -> Profiler {
+        let mut vm = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .engine(engine)
+            .image(assemble(COUNTDOWN).unwrap().obj_image())
+            .build()
+            .unwrap();
+        vm.set_profiler(Some(Profiler::new()));
+        assert_eq!(Ok(()), vm.run());
+        vm.take_profiler().unwrap()
+    }
+
+    #[test]
+    fn counts_every_address_and_opcode() {
+        let profiler = profiled_run(ExecutionEngine::Interpreter);
+        assert_eq!(profiler.total(), 13);
+        assert_eq!(profiler.count(0x3002), 5);
+        assert_eq!(profiler.count(0x3004), 1);
+        assert_eq!(
+            profiler.opcode_counts(),
+            vec![("BR", 5), ("ADD", 6), ("AND", 1), ("TRAP", 1)]
+        );
+        assert_eq!(
+            profiler.hot_spots()[0..2],
+            [
+                HotSpot {
+                    address: 0x3002,
+                    word: 0x103F,
+                    count: 5
+                },
+                HotSpot {
+                    address: 0x3003,
+                    word: 0x03FE,
+                    count: 5
+                }
+            ]
+        );
+        assert_eq!(
+            profiled_run(ExecutionEngine::BasicBlock).hot_spots(),
+            profiler.hot_spots()
+        );
+    }
+
+    #[test]
+    fn report_attributes_addresses_to_labels() {
+        let program = assemble(COUNTDOWN).unwrap();
+        let symbols = SymbolTable::from_symbols(program.symbols);
+        let profiler = profiled_run(ExecutionEngine::Interpreter);
+        assert_eq!(
+            profiler.label_counts(&symbols),
+            vec![
+                (String::from("LOOP"), 11),
+                (String::from("x3000"), 1),
+                (String::from("x3001"), 1)
+            ]
+        );
+        let report = profiler.report(&symbols, 2);
+        assert!(report.starts_with("Profile of 13 instructions\n"));
+        assert!(report.contains("           5  38.46%  x3003   LOOP+1               BRp x3002\n"));
+        assert!(
+            report.contains(
+                "\nADD              6  46.15%  ########################################\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+
+use crate::lc3_vm::VMError;
+
+/// Input byte consumed by the program, with the number of instructions executed when it was consumed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InputEvent {
+    pub instruction: u64,
+    pub byte: u8,
+}
+
+/// Log of the input consumed by a program.
+///
+/// It's stored as text, one event per line with the instruction count and the byte in decimal, so it can be
+/// attached to a bug report and replayed to reproduce the exact same run.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        Self { events: Vec::new() }
+    }
+
+    pub fn record(&mut self, instruction: u64, byte: u8) {
+        self.events.push(InputEvent { instruction, byte });
+    }
+
+    /// Parses a log written with the [`fmt::Display`] format. Empty lines and lines starting with '#' are skipped.
+    pub fn parse(text: &str) -> Result<Self, VMError> {
+        let mut log = Self::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid_line = || VMError::InvalidInputLog(format!("Invalid line {}", index + 1));
+            let (instruction, byte) = line.split_once(' ').ok_or_else(invalid_line)?;
+            log.record(
+                instruction.parse().map_err(|_| invalid_line())?,
+                byte.trim().parse().map_err(|_| invalid_line())?,
+            );
+        }
+        Ok(log)
+    }
+
+    /// Reads a log from the file at path.
+    pub fn load(path: &str) -> Result<Self, VMError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| VMError::InvalidInputLog(format!("{:?}", error)))?;
+        Self::parse(&text)
+    }
+
+    /// Writes the log into the file at path.
+    pub fn save(&self, path: &str) -> Result<(), VMError> {
+        fs::write(path, self.to_string()).map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+}
+
+impl fmt::Display for InputLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{} {}", event.instruction, event.byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Feeds the events of a log back to a program. A key becomes available to the keyboard once the program executed
+/// as many instructions as when it was recorded, and blocking reads get the next byte right away.
+pub struct InputReplay {
+    events: VecDeque<InputEvent>,
+}
+
+impl InputReplay {
+    pub fn new(log: InputLog) -> Self {
+        Self {
+            events: log.events.into(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the next byte for a read that waits until there's input.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        self.events.pop_front().map(|event| event.byte)
+    }
+
+    /// Returns the next byte if it was consumed at instruction or before.
+    pub fn poll(&mut self, instruction: u64) -> Option<u8> {
+        if self.events.front()?.instruction <= instruction {
+            self.next_byte()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_log_text_round_trip() {
+        let mut log = InputLog::new();
+        log.record(12, b'w');
+        log.record(3000, b'\n');
+        assert_eq!(log.to_string(), "12 119\n3000 10\n");
+        assert_eq!(
+            InputLog::parse("# recorded input\n12 119\n\n3000 10\n"),
+            Ok(log)
+        );
+        assert_eq!(
+            InputLog::parse("12 119\n12\n"),
+            Err(VMError::InvalidInputLog(String::from("Invalid line 2")))
+        );
+    }
+
+    #[test]
+    fn replay_releases_keys_at_their_instruction() {
+        let mut log = InputLog::new();
+        log.record(5, b'a');
+        log.record(9, b'b');
+        let mut replay = InputReplay::new(log);
+        assert_eq!(replay.poll(4), None);
+        assert_eq!(replay.poll(5), Some(b'a'));
+        assert_eq!(replay.poll(5), None);
+        assert_eq!(replay.next_byte(), Some(b'b'));
+        assert!(replay.is_finished());
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs;
+
+use crate::hardware::TrapMode;
+use crate::interrupts::{InterruptController, InterruptRequest};
+use crate::lc3_vm::{LC3VirtualMachine, VMError};
+
+/// First bytes of every snapshot file.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"LC3S";
+
+/// Version of the snapshot format written by [`save_snapshot`].
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Reads the words of a snapshot in the order they were written.
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl SnapshotReader<'_> {
+    fn byte(&mut self) -> Result<u8, VMError> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or(VMError::InvalidSnapshot(String::from(
+                "Snapshot is too short",
+            )))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn word(&mut self) -> Result<u16, VMError> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+}
+
+/// Saves the whole state of the vm in a versioned binary snapshot: memory, registers, running flag, origin, PSR,
+/// saved stack pointers, trap mode, timer, pending interrupts and the number of instructions executed, which input
+/// logs are replayed by. The state of the keyboard and the display is
+/// stored in memory, so it's saved too. Every word is stored in big endian format, like in the images.
+pub fn save_snapshot(vm: &LC3VirtualMachine) -> Vec<u8> {
+    let mut words: Vec<u16> = Vec::with_capacity(vm.memory.len() + 32);
+    words.push(SNAPSHOT_VERSION);
+    words.extend_from_slice(&vm.memory);
+    words.extend_from_slice(&vm.registers);
+    words.push(vm.running as u16);
+    words.push(vm.origin);
+    words.push(vm.psr());
+    words.push(vm.saved_ssp);
+    words.push(vm.saved_usp);
+    words.push(match vm.trap_mode() {
+        TrapMode::Native => 0,
+        TrapMode::MemoryResident => 1,
+    });
+    words.push(vm.timer.status());
+    words.push(vm.timer.interval());
+    words.push(vm.timer.count());
+    let pending = vm.interrupts.pending();
+    words.push(pending.len() as u16);
+    for request in pending {
+        words.push(request.vector);
+        words.push(request.priority);
+    }
+    for bytes in vm.instruction_count.to_be_bytes().chunks(2) {
+        words.push(u16::from_be_bytes([bytes[0], bytes[1]]));
+    }
+
+    let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+    for word in words {
+        snapshot.extend_from_slice(&word.to_be_bytes());
+    }
+    snapshot
+}
+
+/// Restores into the vm a snapshot created with [`save_snapshot`]. The console attached to the vm is kept.
+pub fn load_snapshot(vm: &mut LC3VirtualMachine, snapshot: &[u8]) -> Result<(), VMError> {
+    if !snapshot.starts_with(SNAPSHOT_MAGIC) {
+        return Err(VMError::InvalidSnapshot(String::from(
+            "Missing snapshot header",
+        )));
+    }
+    let mut reader = SnapshotReader {
+        bytes: snapshot,
+        position: SNAPSHOT_MAGIC.len(),
+    };
+    let version = reader.word()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(VMError::InvalidSnapshot(format!(
+            "Unsupported version {}",
+            version
+        )));
+    }
+    // The state is read completely before changing the vm, so an invalid snapshot leaves the vm untouched.
+    let mut memory = vec![0; vm.memory.len()];
+    for word in memory.iter_mut() {
+        *word = reader.word()?;
+    }
+    let mut registers = [0; 10];
+    for register in registers.iter_mut() {
+        *register = reader.word()?;
+    }
+    let running = reader.word()? != 0;
+    let origin = reader.word()?;
+    let psr = reader.word()?;
+    let saved_ssp = reader.word()?;
+    let saved_usp = reader.word()?;
+    let trap_mode = match reader.word()? {
+        0 => TrapMode::Native,
+        1 => TrapMode::MemoryResident,
+        value => {
+            return Err(VMError::InvalidSnapshot(format!(
+                "Invalid trap mode {}",
+                value
+            )));
+        }
+    };
+    let (timer_status, timer_interval, timer_count) =
+        (reader.word()?, reader.word()?, reader.word()?);
+    let mut interrupts = InterruptController::new();
+    for _ in 0..reader.word()? {
+        interrupts.raise(InterruptRequest {
+            vector: reader.word()?,
+            priority: reader.word()?,
+        });
+    }
+    let mut instruction_count = 0;
+    for _ in 0..4 {
+        instruction_count = instruction_count << 16 | reader.word()? as u64;
+    }
+    if reader.position != snapshot.len() {
+        return Err(VMError::InvalidSnapshot(String::from(
+            "Unexpected data at the end of the snapshot",
+        )));
+    }
+
+    vm.memory.copy_from_slice(&memory);
+    vm.registers = registers;
+    vm.running = running;
+    vm.origin = origin;
+    vm.set_psr(psr);
+    vm.saved_ssp = saved_ssp;
+    vm.saved_usp = saved_usp;
+    vm.set_trap_mode(trap_mode);
+    vm.timer.restore(timer_status, timer_interval, timer_count);
+    vm.interrupts = interrupts;
+    vm.instruction_count = instruction_count;
+    Ok(())
+}
+
+/// Saves a snapshot of the vm into the file at path.
+pub fn save_snapshot_file(vm: &LC3VirtualMachine, path: &str) -> Result<(), VMError> {
+    fs::write(path, save_snapshot(vm)).map_err(|error| VMError::IOError(format!("{:?}", error)))
+}
+
+/// Restores into the vm the snapshot stored in the file at path.
+pub fn load_snapshot_file(vm: &mut LC3VirtualMachine, path: &str) -> Result<(), VMError> {
+    let snapshot =
+        fs::read(path).map_err(|error| VMError::InvalidSnapshot(format!("{:?}", error)))?;
+    load_snapshot(vm, &snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::hardware::{Privilege, Register};
+    use crate::interrupts::TIMER_INTERRUPT;
+    use crate::replay::InputLog;
+
+    #[test]
+    fn restored_vm_continues_where_the_snapshot_was_taken() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            ADD R2, R2, #1
+            ADD R2, R2, #1
+            LD R0, CHAR
+            OUT
+            HALT
+    CHAR    .FILL x0062
+            .END",
+        )
+        .unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .console(BufferConsole::new(b""))
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        vm.running = true;
+        while vm.registers[Register::PC] != 0x3001 {
+            assert_eq!(Ok(()), vm.step());
+        }
+        vm.raise_interrupt(TIMER_INTERRUPT);
+        vm.set_priority(7);
+        let snapshot = save_snapshot(&vm);
+
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        let mut restored = LC3VirtualMachine::builder()
+            .trap_mode(TrapMode::Native)
+            .console(console)
+            .privilege(Privilege::User)
+            .build()
+            .unwrap();
+        assert_eq!(Ok(()), load_snapshot(&mut restored, &snapshot));
+        assert_eq!(restored.memory, vm.memory);
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.psr(), vm.psr());
+        assert_eq!(restored.trap_mode(), TrapMode::MemoryResident);
+        assert_eq!(restored.interrupts.pending(), &[TIMER_INTERRUPT]);
+        assert_eq!(save_snapshot(&restored), snapshot);
+
+        // The pending interrupt isn't serviced because the processor runs at priority 7.
+        assert_eq!(Ok(()), restored.run());
+        assert_eq!(restored.registers[Register::R2], 2);
+        assert_eq!(*output.lock().unwrap(), b"b");
+    }
+
+    #[test]
+    fn invalid_snapshots_are_rejected() {
+        let mut vm = LC3VirtualMachine::new();
+        let mut snapshot = save_snapshot(&vm);
+        assert!(matches!(
+            load_snapshot(&mut vm, b"LC3X"),
+            Err(VMError::InvalidSnapshot(_))
+        ));
+        assert!(matches!(
+            load_snapshot(&mut vm, &snapshot[..100]),
+            Err(VMError::InvalidSnapshot(_))
+        ));
+        snapshot[5] = 1;
+        assert_eq!(
+            load_snapshot(&mut vm, &snapshot),
+            Err(VMError::InvalidSnapshot(String::from(
+                "Unsupported version 1"
+            )))
+        );
+    }
+
+    #[test]
+    fn replayed_input_arrives_at_the_same_instruction_after_restoring() {
+        let program = assemble(
+            "
+            .ORIG x3000
+            AND R1, R1, #0
+    POLL    ADD R1, R1, #1
+            LDI R2, KBSR
+            BRzp POLL
+            LDI R0, KBDR
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END",
+        )
+        .unwrap();
+        let mut log = InputLog::new();
+        log.record(40, b'a');
+        let build = || {
+            let mut vm = LC3VirtualMachine::builder()
+                .trap_mode(TrapMode::Native)
+                .privilege(Privilege::Supervisor)
+                .console(BufferConsole::new(b""))
+                .image(program.obj_image())
+                .build()
+                .unwrap();
+            vm.replay_input(log.clone());
+            vm
+        };
+
+        let mut vm = build();
+        assert_eq!(Ok(()), vm.run());
+        let polls = vm.registers[Register::R1];
+
+        let mut vm = build();
+        vm.running = true;
+        while vm.instruction_count < 20 {
+            assert_eq!(Ok(()), vm.step());
+        }
+        let snapshot = save_snapshot(&vm);
+        let mut restored = build();
+        assert_eq!(Ok(()), load_snapshot(&mut restored, &snapshot));
+        assert_eq!(restored.instruction_count, 20);
+        assert_eq!(Ok(()), restored.run());
+        assert_eq!(restored.registers[Register::R1], polls);
+        assert_eq!(restored.registers[Register::R0], 'a' as u16);
+    }
+}
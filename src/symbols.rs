@@ -0,0 +1,203 @@
+use std::fs;
+
+use crate::hardware::is_privileged_address;
+use crate::lc3_vm::VMError;
+
+/// Labels of a program with their addresses, used to show addresses by the label they belong to.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct SymbolTable {
+    /// Labels sorted by address.
+    symbols: Vec<(String, u16)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Builds the table from labels and addresses, like the ones in [`crate::AssembledProgram::symbols`].
+    pub fn from_symbols(symbols: impl IntoIterator<Item = (String, u16)>) -> Self {
+        let mut table = Self::new();
+        table.extend(symbols);
+        table
+    }
+
+    /// Adds labels to the table, like the ones of another image loaded in the vm.
+    pub fn extend(&mut self, symbols: impl IntoIterator<Item = (String, u16)>) {
+        self.symbols.extend(symbols);
+        self.symbols.sort_by_key(|(_, address)| *address);
+    }
+
+    /// Parses a symbol table in the format of the .sym files of the LC3 tools, where every symbol is a line with a
+    /// label and its address in hexadecimal, optionally after '//'. Lines after '//' that aren't symbols, like the
+    /// headers, are skipped.
+    pub fn parse(text: &str) -> Result<Self, VMError> {
+        let mut symbols = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let (comment, line) = match line.strip_prefix("//") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [label, address] = fields[..]
+                && let Ok(address) = u16::from_str_radix(address.trim_start_matches(['x', 'X']), 16)
+            {
+                symbols.push((label.to_string(), address));
+            } else if !comment && !line.is_empty() {
+                return Err(VMError::InvalidSymbolFile(format!(
+                    "Invalid line {}",
+                    index + 1
+                )));
+            }
+        }
+        Ok(Self::from_symbols(symbols))
+    }
+
+    /// Reads a symbol table from the file at path.
+    pub fn load(path: &str) -> Result<Self, VMError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| VMError::InvalidSymbolFile(format!("{:?}", error)))?;
+        Self::parse(&text)
+    }
+
+    /// Labels with their addresses, sorted by address.
+    pub fn symbols(&self) -> impl Iterator<Item = (String, u16)> + '_ {
+        self.symbols.iter().cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Returns the address of label.
+    pub fn address(&self, label: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .find(|(name, _)| name == label)
+            .map(|(_, address)| *address)
+    }
+
+    /// Returns the closest label at or before address together with the offset of address from it. Labels of system
+    /// space aren't used for addresses in user space and the other way around, since the code there comes from
+    /// different images.
+    pub fn locate(&self, address: u16) -> Option<(&str, u16)> {
+        let index = self
+            .symbols
+            .partition_point(|(_, symbol_address)| *symbol_address <= address);
+        let (label, symbol_address) = self.symbols[..index].last()?;
+        if is_privileged_address(*symbol_address) != is_privileged_address(address) {
+            return None;
+        }
+        Some((label, address - symbol_address))
+    }
+
+    /// Formats address as its label, with the offset from it if there's any, or as hexadecimal if no label comes
+    /// before it.
+    pub fn describe(&self, address: u16) -> String {
+        match self.locate(address) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => format!("x{:04X}", address),
+        }
+    }
+}
+
+/// Line table of a program, which maps the address of every instruction to its line in the source file.
+///
+/// The file starts with a line with `source` and the path of the source file, followed by a line for every
+/// instruction with its address in hexadecimal and its line number, as written by
+/// [`crate::AssembledProgram::debug_file`].
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct DebugInfo {
+    pub source: String,
+    /// Addresses with their line numbers, sorted by address.
+    pub lines: Vec<(u16, usize)>,
+}
+
+impl DebugInfo {
+    pub fn parse(text: &str) -> Result<Self, VMError> {
+        let mut lines = text.lines().enumerate();
+        let source = lines
+            .next()
+            .and_then(|(_, line)| line.strip_prefix("source "))
+            .ok_or_else(|| VMError::InvalidDebugInfo(String::from("Missing source line")))?;
+        let mut debug_info = Self {
+            source: source.trim().to_string(),
+            lines: Vec::new(),
+        };
+        for (index, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid_line = || VMError::InvalidDebugInfo(format!("Invalid line {}", index + 1));
+            let (address, line_number) = line.trim().split_once(' ').ok_or_else(invalid_line)?;
+            debug_info.lines.push((
+                u16::from_str_radix(address, 16).map_err(|_| invalid_line())?,
+                line_number.trim().parse().map_err(|_| invalid_line())?,
+            ));
+        }
+        debug_info.lines.sort();
+        Ok(debug_info)
+    }
+
+    /// Reads a line table from the file at path.
+    pub fn load(path: &str) -> Result<Self, VMError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| VMError::InvalidDebugInfo(format!("{:?}", error)))?;
+        Self::parse(&text)
+    }
+
+    /// Returns the source line of the instruction at address.
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines
+            .binary_search_by_key(&address, |(line_address, _)| *line_address)
+            .ok()
+            .map(|index| self.lines[index].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn parses_the_sym_files_of_the_assembler() {
+        let program =
+            assemble(".ORIG x3000\nSTART ADD R0, R0, #1\nLOOP BR LOOP\nDATA .FILL #0\n.END")
+                .unwrap();
+        let table = SymbolTable::parse(&program.sym_file()).unwrap();
+        assert_eq!(table, SymbolTable::from_symbols(program.symbols));
+        assert_eq!(table.address("LOOP"), Some(0x3001));
+        assert_eq!(table.describe(0x3001), "LOOP");
+        assert_eq!(table.describe(0x3005), "DATA+3");
+        assert_eq!(table.describe(0x2FFF), "x2FFF");
+        let mut with_os = SymbolTable::from_symbols([(String::from("TRAP_HALT"), 0x0200)]);
+        with_os.extend(table.symbols());
+        assert_eq!(with_os.describe(0x0201), "TRAP_HALT+1");
+        assert_eq!(with_os.describe(0x3000), "START");
+        assert_eq!(with_os.describe(0xFE00), "xFE00");
+        assert!(SymbolTable::parse("not a symbol table").is_err());
+        let without_labels = assemble(".ORIG x3000\nHALT\n.END").unwrap();
+        assert_eq!(
+            SymbolTable::parse(&without_labels.sym_file()),
+            Ok(SymbolTable::new())
+        );
+    }
+
+    #[test]
+    fn parses_the_debug_files_of_the_assembler() {
+        let program = assemble(".ORIG x3000\nLOOP BR LOOP\nHALT\n.END").unwrap();
+        let debug_info = DebugInfo::parse(&program.debug_file("loop.asm")).unwrap();
+        assert_eq!(debug_info.source, "loop.asm");
+        assert_eq!(debug_info.line(0x3001), Some(3));
+        assert_eq!(debug_info.line(0x3002), None);
+        assert_eq!(
+            DebugInfo::parse("source loop.asm\n3000"),
+            Err(VMError::InvalidDebugInfo(String::from("Invalid line 2")))
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+use crate::hardware::StatusBits;
+use crate::interrupts::{InterruptRequest, TIMER_INTERRUPT};
+
+/// Bit of the timer status register that selects the host clock as the source of ticks, one tick per millisecond.
+/// When it's clear the timer ticks once per executed instruction, which makes programs deterministic.
+pub const TIMER_WALL_CLOCK: u16 = 0x0001;
+
+/// Timer device mapped in the device page.
+///
+/// The count register goes up once per tick and, when it reaches the interval, it goes back to zero and the ready
+/// bit of the status register is set. Reading the status register clears the ready bit, and an interval of zero
+/// stops the timer. If the interrupt enable bit is set the timer requests an interrupt while it's ready.
+pub struct Timer {
+    status: u16,
+    interval: u16,
+    count: u16,
+    last_tick: Instant,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            status: 0,
+            interval: 0,
+            count: 0,
+            last_tick: Instant::now(),
+        }
+    }
+
+    /// Returns the status register without acknowledging the timer.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the status register as a program reads it, clearing the ready bit.
+    pub fn read_status(&mut self) -> u16 {
+        let status = self.status;
+        self.status &= !(StatusBits::Ready as u16);
+        status
+    }
+
+    /// Only the interrupt enable and the clock source bits can be written, the ready bit is set by the timer.
+    pub fn write_status(&mut self, value: u16) {
+        let writable = StatusBits::InterruptEnable as u16 | TIMER_WALL_CLOCK;
+        self.status = (self.status & StatusBits::Ready as u16) | (value & writable);
+        self.last_tick = Instant::now();
+    }
+
+    pub fn interval(&self) -> u16 {
+        self.interval
+    }
+
+    /// Sets the number of ticks between expirations and restarts the count.
+    pub fn set_interval(&mut self, interval: u16) {
+        self.interval = interval;
+        self.count = 0;
+        self.last_tick = Instant::now();
+    }
+
+    pub fn count(&self) -> u16 {
+        self.count
+    }
+
+    pub fn set_count(&mut self, count: u16) {
+        self.count = count;
+    }
+
+    /// Restores the three timer registers as they were saved, including the ready bit.
+    pub(crate) fn restore(&mut self, status: u16, interval: u16, count: u16) {
+        self.status = status;
+        self.interval = interval;
+        self.count = count;
+        self.last_tick = Instant::now();
+    }
+
+    /// Advances the timer. It's called once per executed instruction and, with the wall clock source, it counts the
+    /// milliseconds elapsed since the last tick instead.
+    pub fn tick(&mut self) {
+        if self.interval == 0 {
+            return;
+        }
+        let ticks = if self.status & TIMER_WALL_CLOCK != 0 {
+            let elapsed = self.last_tick.elapsed().as_millis() as u64;
+            if elapsed == 0 {
+                return;
+            }
+            self.last_tick += Duration::from_millis(elapsed);
+            elapsed.min(u16::MAX as u64) as u32
+        } else {
+            1
+        };
+        let count = self.count as u32 + ticks;
+        if count >= self.interval as u32 {
+            self.status |= StatusBits::Ready as u16;
+        }
+        self.count = (count % self.interval as u32) as u16;
+    }
+
+    /// Returns the interrupt requested by the timer, if it's ready and interrupts are enabled.
+    pub fn interrupt_request(&self) -> Option<InterruptRequest> {
+        let requesting = StatusBits::Ready as u16 | StatusBits::InterruptEnable as u16;
+        if self.status & requesting == requesting {
+            Some(TIMER_INTERRUPT)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn timer_counts_instructions() {
+        let mut timer = Timer::new();
+        timer.tick();
+        assert_eq!(timer.count(), 0); // Stopped with interval zero
+        timer.set_interval(3);
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.count(), 2);
+        assert_eq!(timer.status() & StatusBits::Ready as u16, 0);
+        timer.tick();
+        assert_eq!(timer.count(), 0);
+        assert_eq!(timer.read_status(), StatusBits::Ready as u16);
+        assert_eq!(timer.read_status(), 0);
+    }
+
+    #[test]
+    fn timer_requests_interrupt_while_ready() {
+        let mut timer = Timer::new();
+        timer.write_status(0xFFFF);
+        assert_eq!(timer.status(), 0x4001);
+        timer.write_status(StatusBits::InterruptEnable as u16);
+        timer.set_interval(1);
+        assert_eq!(timer.interrupt_request(), None);
+        timer.tick();
+        assert_eq!(timer.interrupt_request(), Some(TIMER_INTERRUPT));
+        timer.read_status();
+        assert_eq!(timer.interrupt_request(), None);
+    }
+
+    #[test]
+    fn timer_counts_milliseconds_with_wall_clock() {
+        let mut timer = Timer::new();
+        timer.write_status(TIMER_WALL_CLOCK);
+        timer.set_interval(1000);
+        timer.tick();
+        thread::sleep(Duration::from_millis(20));
+        timer.tick();
+        assert!(timer.count() >= 20);
+        assert_eq!(timer.status() & StatusBits::Ready as u16, 0);
+    }
+}
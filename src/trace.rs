@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+
+use crate::disassembler::disassemble_instruction;
+use crate::lc3_vm::VMError;
+
+/// First bytes of every binary trace.
+pub const TRACE_MAGIC: &[u8; 4] = b"LC3T";
+
+/// Version of the binary trace format.
+pub const TRACE_VERSION: u16 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    /// One JSON object per line and instruction.
+    JsonLines,
+    /// Magic bytes and version followed by one record per instruction, every word in big endian format:
+    /// PC, instruction word, a byte with a bit set for every register R0 to R7 written, a byte with bit 0 set if COND
+    /// changed, the value of each register written, the new COND if it changed, the number of memory reads, the
+    /// number of memory writes and then an address and value pair for every read and every write.
+    Binary,
+}
+
+/// Memory access made by an instruction, with the value read or written.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub value: u16,
+}
+
+/// Change of the condition codes made by an instruction.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct CondChange {
+    pub from: u16,
+    pub to: u16,
+}
+
+/// Effects of an executed instruction.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct TraceRecord {
+    /// Instruction count of the vm when the instruction was executed.
+    pub instruction: u64,
+    pub pc: u16,
+    pub word: u16,
+    pub text: String,
+    /// General purpose registers whose value changed, with their new value.
+    pub registers: BTreeMap<String, u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cond: Option<CondChange>,
+    pub reads: Vec<MemoryAccess>,
+    pub writes: Vec<MemoryAccess>,
+}
+
+/// Writes a record of every instruction the vm executes, optionally only for the instructions in some address ranges.
+///
+/// Memory accesses are those made by the instruction itself, not counting the fetch: the trap vector and the stack
+/// accessed by TRAP and RTI and the strings read by the native trap routines are included. Accesses made while
+/// starting an interrupt service routine, or the one of an access control violation, aren't part of any record.
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    format: TraceFormat,
+    ranges: Vec<RangeInclusive<u16>>,
+    started: bool,
+    /// Whether an instruction that is traced is executing, so its memory accesses are recorded.
+    recording: bool,
+    reads: Vec<MemoryAccess>,
+    writes: Vec<MemoryAccess>,
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + Send + 'static, format: TraceFormat) -> Self {
+        Self {
+            output: Box::new(output),
+            format,
+            ranges: Vec::new(),
+            started: false,
+            recording: false,
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Only traces the instructions stored in range. If it's called several times instructions in any of the ranges
+    /// are traced, and if it's never called every instruction is traced.
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.ranges.push(range);
+        self
+    }
+
+    /// Checks if the instruction at pc has to be traced.
+    pub fn traces(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+
+    /// Starts recording the memory accesses of a traced instruction. Accesses made outside of traced instructions
+    /// aren't recorded, so untraced code doesn't pile them up.
+    pub(crate) fn begin_instruction(&mut self) {
+        self.recording = true;
+        self.reads.clear();
+        self.writes.clear();
+    }
+
+    pub(crate) fn record_read(&mut self, address: u16, value: u16) {
+        if self.recording {
+            self.reads.push(MemoryAccess { address, value });
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, address: u16, value: u16) {
+        if self.recording {
+            self.writes.push(MemoryAccess { address, value });
+        }
+    }
+
+    /// Writes the record of the instruction at pc, comparing the registers before and after it was executed.
+    pub(crate) fn end_instruction(
+        &mut self,
+        instruction: u64,
+        pc: u16,
+        word: u16,
+        before: &[u16; 10],
+        after: &[u16; 10],
+    ) -> Result<(), VMError> {
+        self.recording = false;
+        let record = TraceRecord {
+            instruction,
+            pc,
+            word,
+            text: disassemble_instruction(pc, word),
+            registers: (0..8)
+                .filter(|index| before[*index] != after[*index])
+                .map(|index| (format!("R{}", index), after[index]))
+                .collect(),
+            cond: (before[9] != after[9]).then_some(CondChange {
+                from: before[9],
+                to: after[9],
+            }),
+            reads: std::mem::take(&mut self.reads),
+            writes: std::mem::take(&mut self.writes),
+        };
+        self.write_record(&record)
+    }
+
+    pub fn write_record(&mut self, record: &TraceRecord) -> Result<(), VMError> {
+        let bytes = match self.format {
+            TraceFormat::JsonLines => {
+                let mut line = serde_json::to_vec(record)
+                    .map_err(|error| VMError::IOError(format!("{:?}", error)))?;
+                line.push(b'\n');
+                line
+            }
+            TraceFormat::Binary => {
+                let mut bytes = Vec::new();
+                if !self.started {
+                    bytes.extend_from_slice(TRACE_MAGIC);
+                    bytes.extend_from_slice(&TRACE_VERSION.to_be_bytes());
+                }
+                bytes.extend_from_slice(&encode_record(record));
+                bytes
+            }
+        };
+        self.started = true;
+        self.output
+            .write_all(&bytes)
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+
+    pub fn flush(&mut self) -> Result<(), VMError> {
+        self.output
+            .flush()
+            .map_err(|error| VMError::IOError(format!("{:?}", error)))
+    }
+}
+
+/// Encodes a record in the binary format described in [`TraceFormat::Binary`].
+fn encode_record(record: &TraceRecord) -> Vec<u8> {
+    let mut words = vec![record.pc, record.word];
+    let mut register_mask = 0u8;
+    let mut register_values = Vec::new();
+    for index in 0..8 {
+        if let Some(value) = record.registers.get(&format!("R{}", index)) {
+            register_mask |= 1 << index;
+            register_values.push(*value);
+        }
+    }
+    words.push(u16::from_be_bytes([
+        register_mask,
+        record.cond.is_some() as u8,
+    ]));
+    words.extend(register_values);
+    words.extend(record.cond.map(|cond| cond.to));
+    words.push(record.reads.len() as u16);
+    words.push(record.writes.len() as u16);
+    for access in record.reads.iter().chain(&record.writes) {
+        words.push(access.address);
+        words.push(access.value);
+    }
+    words.iter().flat_map(|word| word.to_be_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::hardware::TrapMode;
+    use crate::lc3_vm::LC3VirtualMachine;
+
+    /// Writer that keeps what was written, so it can be checked after the tracer was moved into the vm.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced_run(trap_mode: TrapMode, tracer: impl FnOnce(SharedBuffer) -> Tracer) -> Vec<u8> {
+        let program = assemble(
+            "
+            .ORIG x3000
+            LD R1, VALUE
+            ADD R1, R1, #-1
+            ST R1, VALUE
+            STI R1, POINTER
+            OUT
+            HALT
+    VALUE   .FILL x0005
+    POINTER .FILL x3100
+            .END",
+        )
+        .unwrap();
+        let mut vm = LC3VirtualMachine::builder()
+            .trap_mode(trap_mode)
+            .console(BufferConsole::new(b""))
+            .image(program.obj_image())
+            .build()
+            .unwrap();
+        let buffer = SharedBuffer::default();
+        vm.set_tracer(Some(tracer(buffer.clone())));
+        assert_eq!(Ok(()), vm.run());
+        buffer.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn json_lines_trace_every_effect() {
+        let trace = traced_run(TrapMode::MemoryResident, |buffer| {
+            Tracer::new(buffer, TraceFormat::JsonLines)
+        });
+        let lines: Vec<&str> = std::str::from_utf8(&trace).unwrap().lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"instruction":1,"pc":12288,"word":8709,"text":"LD R1, x3006","registers":{"R1":5},"reads":[{"address":12294,"value":5}],"writes":[]}"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"{"instruction":4,"pc":12291,"word":45571,"text":"STI R1, x3007","registers":{},"reads":[{"address":12295,"value":12544}],"writes":[{"address":12544,"value":4}]}"#
+        );
+        // TRAP reads the trap vector and pushes the PSR and the PC onto the supervisor stack, where RTI pops them.
+        assert_eq!(
+            lines[4],
+            r#"{"instruction":5,"pc":12292,"word":61473,"text":"OUT","registers":{"R6":12286,"R7":12293},"reads":[{"address":33,"value":516}],"writes":[{"address":12287,"value":32769},{"address":12286,"value":12293}]}"#
+        );
+        assert_eq!(
+            lines.iter().find(|line| line.contains(r#""text":"RTI""#)),
+            Some(
+                &r#"{"instruction":15,"pc":519,"word":32768,"text":"RTI","registers":{"R6":0},"reads":[{"address":12286,"value":12293},{"address":12287,"value":32769}],"writes":[]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn filtered_records_only_have_the_accesses_of_their_instruction() {
+        let trace = traced_run(TrapMode::Native, |buffer| {
+            Tracer::new(buffer, TraceFormat::JsonLines).with_range(0x3002..=0x3002)
+        });
+        let lines: Vec<&str> = std::str::from_utf8(&trace).unwrap().lines().collect();
+        // The read of LD, which isn't traced, doesn't end up in the record of ST.
+        assert_eq!(
+            lines,
+            [
+                r#"{"instruction":3,"pc":12290,"word":12803,"text":"ST R1, x3006","registers":{},"reads":[],"writes":[{"address":12294,"value":4}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_trace_filtered_by_address() {
+        let trace = traced_run(TrapMode::Native, |buffer| {
+            Tracer::new(buffer, TraceFormat::Binary).with_range(0x3001..=0x3001)
+        });
+        // ADD R1, R1, #-1 writes R1 (mask 0b10) and leaves COND positive, so only R1 is recorded.
+        let mut expected = b"LC3T".to_vec();
+        expected.extend_from_slice(&[
+            0x00, 0x02, 0x30, 0x01, 0x12, 0x7F, 0b10, 0, 0x00, 0x04, 0, 0, 0, 0,
+        ]);
+        assert_eq!(trace, expected);
+    }
+}